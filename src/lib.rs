@@ -2,7 +2,7 @@
 //! # Pulling lfs files from git
 //! This crate allows to download lfs files.
 //! Please check [`repo_tools::glob_recurse_pull_directory`] for complete directories and [`repo_tools::pull_file`] for single files.
-//! It really is the minimal viable example featuring only our own simple use-case: token / bearer auth and pulling a single file per request.
+//! Authentication is pluggable through [`AuthProvider`], without one the git credential helpers are asked.
 #![warn(missing_docs)]
 #![warn(unused_qualifications)]
 #![deny(deprecated)]
@@ -11,6 +11,11 @@ mod repo_tools;
 
 /// The prelude to set everything up for calling any crate functions
 pub mod prelude {
+    pub use crate::repo_tools::AuthProvider;
+    pub use crate::repo_tools::BatchRef;
+    pub use crate::repo_tools::ConnectionOptions;
+    pub use crate::repo_tools::PullOptions;
+    pub use crate::repo_tools::TlsOptions;
     use std::fmt::{Display, Formatter};
     use vg_errortools::FatIOError;
 
@@ -23,6 +28,10 @@ pub mod prelude {
         UsedLocalCache,
         /// File was already pulled
         WasAlreadyPresent,
+        /// The object was downloaded once for another file of the same pull and linked from the cache
+        Deduplicated,
+        /// The pointer names its object with another hash than sha256 and was left as it is
+        UnsupportedHash,
     }
 
     impl Display for FilePullMode {
@@ -31,6 +40,10 @@ pub mod prelude {
                 FilePullMode::DownloadedFromRemote => write!(f, "Downloaded from lfs server"),
                 FilePullMode::UsedLocalCache => write!(f, "Taken from local cache"),
                 FilePullMode::WasAlreadyPresent => write!(f, "File already pulled"),
+                FilePullMode::Deduplicated => {
+                    write!(f, "Shares its download with another file")
+                }
+                FilePullMode::UnsupportedHash => write!(f, "Hash algorithm not supported"),
             }
         }
     }
@@ -60,6 +73,15 @@ pub mod prelude {
         /// It may indicate a mitm-attack
         #[error("Checksum incorrect")]
         ChecksumMismatch,
+        /// The downloaded data does not have the size of the pointer, `actual` counts the bytes
+        /// received until the download was aborted
+        #[error("Size incorrect: expected {expected} bytes, received {actual}")]
+        SizeMismatch {
+            /// The size from the pointer file
+            expected: usize,
+            /// The size of the received data
+            actual: usize,
+        },
         /// Somehow decoding the oid in the file was not possible, maybe repo integrity is not ensured
         #[error("Could not decode oid-string to bytes: {0}")]
         OidNotValidHex(#[from] hex::FromHexError),
@@ -87,6 +109,69 @@ pub mod prelude {
         /// Timeout error
         #[error("Download failed due to timeout")]
         Timeout,
+        /// A git config file could not be parsed
+        #[error("Invalid git config: {0}")]
+        InvalidGitConfig(String),
+        /// The requested git remote has no url configured
+        #[error("Remote '{0}' has no url configured")]
+        UnknownRemote(String),
+        /// Running `git-lfs-authenticate` over ssh failed or returned garbage
+        #[error("SSH authentication failed: {0}")]
+        SshAuthenticationFailed(String),
+        /// The `git-lfs-transfer` ssh protocol failed
+        #[error("SSH transfer error: {0}")]
+        SshTransferError(String),
+        /// A git credential helper could not be run
+        #[error("Credential helper failed: {0}")]
+        CredentialHelperFailed(String),
+        /// The command to get an access token failed or printed nothing
+        #[error("Token command failed: {0}")]
+        TokenCommandFailed(String),
+        /// A CA certificate, client certificate or key for TLS could not be used
+        #[error("Invalid TLS configuration: {0}")]
+        InvalidTlsConfig(String),
+        /// The LFS server does not have the object (object error 404)
+        #[error("Object {oid} not found on the LFS server: {message}")]
+        ObjectNotFound {
+            /// The oid of the object
+            oid: String,
+            /// The message of the LFS server
+            message: String,
+        },
+        /// The object was removed from the LFS server (object error 410)
+        #[error("Object {oid} was removed from the LFS server: {message}")]
+        ObjectRemoved {
+            /// The oid of the object
+            oid: String,
+            /// The message of the LFS server
+            message: String,
+        },
+        /// The LFS server rejected the object, e.g. because its size does not match (object error 422)
+        #[error("Object {oid} is invalid: {message}")]
+        ObjectInvalid {
+            /// The oid of the object
+            oid: String,
+            /// The message of the LFS server
+            message: String,
+        },
+        /// The storage quota of the LFS server is used up (object error 507)
+        #[error("Storage quota exceeded for object {oid}: {message}")]
+        StorageQuotaExceeded {
+            /// The oid of the object
+            oid: String,
+            /// The message of the LFS server
+            message: String,
+        },
+        /// Any other error the LFS server returned for a single object
+        #[error("LFS server error {code} for object {oid}: {message}")]
+        ObjectError {
+            /// The oid of the object
+            oid: String,
+            /// The http status code of the object error
+            code: u16,
+            /// The message of the LFS server
+            message: String,
+        },
     }
 }
 pub use prelude::FilePullMode;
@@ -96,6 +181,20 @@ pub use prelude::LFSError;
 pub use repo_tools::glob_recurse_pull_directory;
 #[doc(inline)]
 pub use repo_tools::pull_file;
+#[doc(inline)]
+pub use repo_tools::recurse_pull_submodules;
+#[doc(inline)]
+pub use repo_tools::BatchRef;
+#[doc(inline)]
+pub use repo_tools::ConnectionOptions;
+#[doc(inline)]
+pub use repo_tools::PullOptions;
+#[doc(inline)]
+pub use repo_tools::SubmodulePullResult;
+#[doc(inline)]
+pub use repo_tools::TlsOptions;
+#[doc(inline)]
+pub use repo_tools::{AuthProvider, BasicAuth, BearerAuth, HeaderAuth, NoAuth, TokenCommandAuth};
 
 impl From<&'static str> for LFSError {
     fn from(message: &'static str) -> Self {
@@ -1,5 +1,6 @@
 use clap::Parser;
 use lfspull::prelude::*;
+use lfspull::{BasicAuth, BearerAuth, HeaderAuth, NoAuth, TlsOptions, TokenCommandAuth};
 use std::path::PathBuf;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -11,9 +12,29 @@ struct Args {
     ///File to pull, must be a valid lfs-file inside the git repo
     #[clap(short, long)]
     file_to_pull: Option<PathBuf>,
-    /// The access token for https-bearer-auth to the repo
+    /// The access token for https-auth to the repo, sent as password of --username or as bearer token
     #[clap(short, long, env = "ACCESS_TOKEN")]
     access_token: Option<String>,
+
+    ///username for basic auth with the access token
+    #[clap(long, default_value = "oauth2")]
+    username: String,
+
+    ///send the access token as `Authorization: Bearer` header instead of basic auth
+    #[clap(long)]
+    bearer: bool,
+
+    ///shell command printing an access token, run again when the server denies the token
+    #[clap(long, conflicts_with = "access_token")]
+    token_command: Option<String>,
+
+    ///static header to authenticate with, e.g. "Private-Token: abc"
+    #[clap(long, conflicts_with_all = ["access_token", "token_command"])]
+    auth_header: Option<String>,
+
+    ///send no credentials, without this the git credential helpers are asked if no token is given
+    #[clap(long, conflicts_with_all = ["access_token", "token_command", "auth_header"])]
+    no_auth: bool,
     /// A recursive pull pattern with asterisks (e.g. /path/to/repo/**/*.jpg)
     #[clap(short, long)]
     recurse_pattern: Option<String>,
@@ -35,6 +56,63 @@ struct Args {
     ///When 0 given, there is no timeout
     #[clap(short, long)]
     timeout: Option<u64>,
+
+    ///git remote to pull from, defaults to the upstream remote of the current branch or origin
+    #[clap(long)]
+    remote: Option<String>,
+
+    ///allow plain http LFS endpoints, otherwise http remotes are upgraded to https
+    #[clap(long)]
+    allow_http: bool,
+
+    ///ref sent to the LFS server, defaults to the upstream branch of HEAD
+    #[clap(long = "ref")]
+    git_ref: Option<String>,
+
+    ///do not send any ref to the LFS server
+    #[clap(long, conflicts_with = "git_ref")]
+    no_ref: bool,
+
+    ///pull the repository in the current directory and all initialised submodules, each with its own remote.
+    ///The recurse pattern is applied relative to every work tree and defaults to **/*
+    #[clap(long)]
+    recurse_submodules: bool,
+
+    ///PEM file with extra CA certificates to trust, overrides http.sslCAInfo
+    #[clap(long)]
+    ssl_ca_info: Option<PathBuf>,
+
+    ///directory of PEM CA certificates to trust, overrides http.sslCAPath
+    #[clap(long)]
+    ssl_ca_path: Option<PathBuf>,
+
+    ///PEM client certificate for mutual TLS, overrides http.sslCert
+    #[clap(long)]
+    ssl_cert: Option<PathBuf>,
+
+    ///PKCS#8 PEM key of the client certificate, overrides http.sslKey
+    #[clap(long, requires = "ssl_cert")]
+    ssl_key: Option<PathBuf>,
+
+    ///do not verify the TLS certificate of the LFS server, overrides http.sslVerify
+    #[clap(long)]
+    ssl_no_verify: bool,
+
+    ///seconds to wait for a connection to the LFS server, 0 waits as long as the download timeout allows
+    #[clap(long, default_value_t = 30)]
+    connect_timeout: u64,
+
+    ///speak HTTP/2 without negotiating it first, only for servers known to support it
+    #[clap(long)]
+    http2_prior_knowledge: bool,
+
+    ///number of objects requested per batch API call when pulling many files
+    #[clap(long, default_value_t = 100)]
+    batch_size: usize,
+
+    ///number of files downloaded at the same time, defaults to lfs.concurrenttransfers or 8
+    #[clap(short, long)]
+    jobs: Option<usize>,
 }
 
 #[tokio::main]
@@ -55,29 +133,86 @@ pub async fn main() -> Result<(), LFSError> {
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let access_token = args.access_token.as_deref();
+    let auth: Option<Box<dyn AuthProvider>> = if let Some(token) = args.access_token {
+        if args.bearer {
+            Some(Box::new(BearerAuth::new(token)))
+        } else {
+            Some(Box::new(BasicAuth::new(args.username, token)))
+        }
+    } else if let Some(token_command) = args.token_command {
+        let auth = TokenCommandAuth::new(token_command);
+        if args.bearer {
+            Some(Box::new(auth))
+        } else {
+            Some(Box::new(auth.with_username(args.username)))
+        }
+    } else if let Some(header) = args.auth_header {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or(LFSError::InvalidFormat("auth header must be 'Name: value'"))?;
+        Some(Box::new(HeaderAuth::new(name.trim(), value.trim())))
+    } else if args.no_auth {
+        Some(Box::new(NoAuth))
+    } else {
+        None
+    };
+    let auth = auth.as_deref();
+    let options = PullOptions {
+        max_retry: args.max_retry,
+        randomizer_bytes: args.random_bytes,
+        timeout: args.timeout,
+        remote: args.remote,
+        allow_http: args.allow_http,
+        git_ref: match args.git_ref {
+            Some(name) => BatchRef::Name(name),
+            None if args.no_ref => BatchRef::Omit,
+            None => BatchRef::FromHead,
+        },
+        tls: TlsOptions {
+            ca_info: args.ssl_ca_info,
+            ca_path: args.ssl_ca_path,
+            cert: args.ssl_cert,
+            key: args.ssl_key,
+            verify: args.ssl_no_verify.then_some(false),
+        },
+        connection: ConnectionOptions {
+            connect_timeout: Some(args.connect_timeout).filter(|seconds| *seconds > 0),
+            http2_prior_knowledge: args.http2_prior_knowledge,
+            ..Default::default()
+        },
+        batch_size: args.batch_size,
+        concurrency: args.jobs,
+    };
     if let Some(file) = args.file_to_pull {
         info!("Single file mode: {}", file.to_string_lossy());
-        let result = lfspull::pull_file(
-            file,
-            access_token,
-            args.max_retry,
-            args.random_bytes,
-            args.timeout,
-        )
-        .await?;
+        let result = lfspull::pull_file(file, auth, &options).await?;
         info!("Result: {}", result);
     }
-    if let Some(recurse_pattern) = args.recurse_pattern {
+    if args.recurse_submodules {
+        let recurse_pattern = args.recurse_pattern.as_deref().unwrap_or("**/*");
+        info!("Submodule-recurse mode: {}", recurse_pattern);
+        let results =
+            lfspull::recurse_pull_submodules(".", recurse_pattern, auth, &options).await?;
+        info!("Pulling finished! Listing submodules, files and sources: ");
+
+        for result in results {
+            info!(
+                "{} ({})",
+                result.name.as_deref().unwrap_or("<root>"),
+                result.path.to_string_lossy()
+            );
+            result
+                .files
+                .into_iter()
+                .enumerate()
+                .for_each(|(id, (n, r))| {
+                    info!("  {id} - '{n}': {r}");
+                });
+        }
+    } else if let Some(recurse_pattern) = args.recurse_pattern {
         info!("Glob-recurse mode: {}", &recurse_pattern);
-        let results = lfspull::glob_recurse_pull_directory(
-            &recurse_pattern,
-            access_token,
-            args.max_retry,
-            args.random_bytes,
-            args.timeout,
-        )
-        .await?;
+        let results =
+            lfspull::glob_recurse_pull_directory(&recurse_pattern, auth, &options).await?;
         info!("Pulling finished! Listing files and sources: ");
 
         results.into_iter().enumerate().for_each(|(id, (n, r))| {
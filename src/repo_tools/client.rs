@@ -0,0 +1,141 @@
+use crate::prelude::*;
+use crate::repo_tools::endpoint::LfsEndpoint;
+use crate::repo_tools::proxy::ProxySettings;
+use crate::repo_tools::tls::TlsOptions;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Connection settings of the HTTP client shared by all downloads of a pull
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// Idle connections kept open per host for the following requests
+    pub pool_max_idle_per_host: usize,
+    /// Seconds an idle connection is kept open, `None` keeps it until the server closes it
+    pub pool_idle_timeout: Option<u64>,
+    /// Seconds between TCP and HTTP/2 keep-alive probes, `None` disables them
+    pub keep_alive: Option<u64>,
+    /// Seconds to wait for a connection to be established, `None` waits as long as the download timeout allows
+    pub connect_timeout: Option<u64>,
+    /// Speak HTTP/2 right away instead of negotiating it, only for servers known to support it
+    pub http2_prior_knowledge: bool,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Some(90),
+            keep_alive: Some(60),
+            connect_timeout: Some(30),
+            http2_prior_knowledge: false,
+        }
+    }
+}
+
+/// The HTTP clients of a pull session, one per TLS and proxy setup, so connections are pooled
+/// across all downloads. HTTP/2 is used when the server offers it, multiplexing requests on one connection.
+pub struct HttpClients {
+    options: ConnectionOptions,
+    clients: Mutex<HashMap<(TlsOptions, ProxySettings), Client>>,
+}
+
+impl HttpClients {
+    pub fn new(options: &ConnectionOptions) -> Self {
+        HttpClients {
+            options: options.clone(),
+            clients: Mutex::default(),
+        }
+    }
+
+    /// The client for the TLS and proxy settings of `lfs_endpoint`, created on first use
+    pub async fn get(&self, lfs_endpoint: &LfsEndpoint) -> Result<Client, LFSError> {
+        let key = (lfs_endpoint.tls.clone(), lfs_endpoint.proxy.clone());
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        debug!("Creating http client for {}", lfs_endpoint.url);
+        let mut builder = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .pool_max_idle_per_host(self.options.pool_max_idle_per_host)
+            .pool_idle_timeout(self.options.pool_idle_timeout.map(Duration::from_secs))
+            .tcp_keepalive(self.options.keep_alive.map(Duration::from_secs))
+            .http2_keep_alive_interval(self.options.keep_alive.map(Duration::from_secs))
+            .http2_adaptive_window(true);
+        if let Some(seconds) = self.options.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(seconds));
+        }
+        if self.options.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        let builder = key.0.apply(builder).await?;
+        let client = key.1.apply(builder)?.build()?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn connections_are_reused_across_downloads() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        let read = stream.read(&mut buf).await.unwrap();
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..read]);
+                        if request.ends_with(b"\r\n\r\n") {
+                            request.clear();
+                            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                            stream.write_all(response.as_bytes()).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let clients = HttpClients::new(&ConnectionOptions::default());
+        let endpoint = LfsEndpoint {
+            url: url.clone(),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            let client = clients.get(&endpoint).await.unwrap();
+            let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "ok");
+            // the connection goes back to the pool in the background once the body is read
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let other_tls = LfsEndpoint {
+            tls: TlsOptions {
+                verify: Some(false),
+                ..Default::default()
+            },
+            ..endpoint
+        };
+        clients.get(&other_tls).await.unwrap();
+        assert_eq!(clients.clients.lock().await.len(), 2);
+    }
+}
@@ -0,0 +1,555 @@
+use crate::prelude::*;
+use crate::repo_tools::auth::{AuthProvider, BasicAuth};
+use crate::repo_tools::git_config::{parse_bool, GitConfig};
+use crate::repo_tools::netrc::NetrcLogin;
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{LazyLock, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::debug;
+use url::Url;
+
+/// Credentials which were accepted by a server during this session, so helpers run once per endpoint
+static CREDENTIAL_CACHE: LazyLock<Mutex<HashMap<String, Credential>>> =
+    LazyLock::new(Default::default);
+
+/// The attributes exchanged with credential helpers, see `git help credential`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credential {
+    pub protocol: String,
+    /// Host including a non-default port
+    pub host: String,
+    /// Only sent with `credential.useHttpPath`
+    pub path: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Credential {
+    fn for_url(url: &Url, use_http_path: bool) -> Self {
+        let host = url.host_str().unwrap_or_default();
+        Credential {
+            protocol: url.scheme().to_owned(),
+            host: match url.port() {
+                Some(port) => format!("{host}:{port}"),
+                None => host.to_owned(),
+            },
+            path: use_http_path.then(|| url.path().trim_start_matches('/').to_owned()),
+            username: Some(url.username().to_owned()).filter(|user| !user.is_empty()),
+            password: None,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.username.is_some() && self.password.is_some()
+    }
+
+    fn cache_key(&self) -> String {
+        format!(
+            "{}://{}/{}",
+            self.protocol,
+            self.host,
+            self.path.as_deref().unwrap_or_default()
+        )
+    }
+
+    fn to_input(&self) -> String {
+        let mut input = format!("protocol={}\nhost={}\n", self.protocol, self.host);
+        let optional = [
+            ("path", &self.path),
+            ("username", &self.username),
+            ("password", &self.password),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                input.push_str(&format!("{key}={value}\n"));
+            }
+        }
+        input.push('\n');
+        input
+    }
+
+    /// Takes over the attributes a helper answered with, returns `true` if it asked to stop
+    fn update(&mut self, output: &str) -> bool {
+        let mut quit = false;
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                "protocol" => self.protocol = value.to_owned(),
+                "host" => self.host = value.to_owned(),
+                "path" => self.path = Some(value.to_owned()),
+                "username" => self.username = Some(value.to_owned()),
+                "password" => self.password = Some(value.to_owned()),
+                "quit" => quit = parse_bool(value).unwrap_or(false),
+                _ => {}
+            }
+        }
+        quit
+    }
+
+    /// The `Authorization` header value for basic auth with these credentials
+    pub fn authorization(&self) -> String {
+        BasicAuth::new(
+            self.username.as_deref().unwrap_or_default(),
+            self.password.as_deref().unwrap_or_default(),
+        )
+        .header_value()
+    }
+}
+
+/// The `credential.helper`s configured for an url
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CredentialHelpers {
+    helpers: Vec<String>,
+    request: Credential,
+}
+
+impl CredentialHelpers {
+    /// Collects `credential.helper`, `credential.useHttpPath` and `credential.username`, also from
+    /// `credential.<url>.*` sections matching `url`. An empty helper clears the ones before it.
+    pub fn from_config(config: &GitConfig, url: &Url) -> Self {
+        let mut helpers = Vec::new();
+        for helper in config.get_all_for_url("credential", "helper", url) {
+            if helper.is_empty() {
+                helpers.clear();
+            } else {
+                helpers.push(helper.to_owned());
+            }
+        }
+        let use_http_path = config
+            .get_all_for_url("credential", "usehttppath", url)
+            .pop()
+            .and_then(parse_bool)
+            .unwrap_or(false);
+        let mut request = Credential::for_url(url, use_http_path);
+        if request.username.is_none() {
+            request.username = config
+                .get_all_for_url("credential", "username", url)
+                .pop()
+                .map(str::to_owned);
+        }
+        CredentialHelpers { helpers, request }
+    }
+
+    /// Credentials which already worked for this url during this session
+    pub fn cached(&self) -> Option<Credential> {
+        CREDENTIAL_CACHE
+            .lock()
+            .expect("credential cache poisoned")
+            .get(&self.request.cache_key())
+            .cloned()
+    }
+
+    /// Asks the helpers in order until one answers with username and password, like `git credential fill`
+    pub async fn fill(&self) -> Result<Option<Credential>, LFSError> {
+        let mut credential = self.request.clone();
+        for helper in &self.helpers {
+            let output = run_helper(helper, "get", &credential.to_input()).await?;
+            let quit = credential.update(&output);
+            if credential.is_complete() {
+                return Ok(Some(credential));
+            }
+            if quit {
+                debug!("Credential helper '{helper}' asked to quit");
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tells all helpers to store `credential` after the server accepted it, like `git credential approve`
+    pub async fn approve(&self, credential: &Credential) {
+        CREDENTIAL_CACHE
+            .lock()
+            .expect("credential cache poisoned")
+            .insert(self.request.cache_key(), credential.clone());
+        self.notify("store", credential).await;
+    }
+
+    /// Tells all helpers to forget `credential` after the server denied it, like `git credential reject`
+    pub async fn reject(&self, credential: &Credential) {
+        CREDENTIAL_CACHE
+            .lock()
+            .expect("credential cache poisoned")
+            .remove(&self.request.cache_key());
+        self.notify("erase", credential).await;
+    }
+
+    async fn notify(&self, operation: &str, credential: &Credential) {
+        for helper in &self.helpers {
+            if let Err(e) = run_helper(helper, operation, &credential.to_input()).await {
+                debug!("Credential helper '{helper}' failed to {operation}: {e}");
+            }
+        }
+    }
+}
+
+/// Where the credential currently sent by [`GitCredentialAuth`] comes from
+#[derive(Debug, Clone)]
+enum CredentialState {
+    /// Nothing is sent
+    Anonymous,
+    /// From a credential helper, `true` once the server accepted it
+    Helper(Credential, bool),
+    /// From `.netrc`
+    Netrc(Credential),
+    /// Every source was denied
+    Exhausted,
+}
+
+/// Authenticates like git does without an explicit token: nothing is sent until the server denies access,
+/// then the credential helpers are asked and after them the `.netrc` login. Helper credentials are approved
+/// once accepted and rejected when denied.
+pub struct GitCredentialAuth {
+    helpers: CredentialHelpers,
+    netrc: Option<Credential>,
+    /// Held while helpers run, so concurrent denials move the state forward only once
+    state: tokio::sync::Mutex<CredentialState>,
+}
+
+impl GitCredentialAuth {
+    /// Starts with the helper credential that already worked for this url during the session, if any
+    pub fn new(helpers: &CredentialHelpers, netrc: Option<&NetrcLogin>) -> Self {
+        let netrc = netrc.map(|login| Credential {
+            username: login.login.clone(),
+            password: login.password.clone(),
+            ..helpers.request.clone()
+        });
+        let state = match helpers.cached() {
+            Some(credential) => CredentialState::Helper(credential, true),
+            None => CredentialState::Anonymous,
+        };
+        GitCredentialAuth {
+            helpers: helpers.clone(),
+            netrc,
+            state: tokio::sync::Mutex::new(state),
+        }
+    }
+
+    fn headers_for(state: &CredentialState) -> HashMap<String, String> {
+        match state {
+            CredentialState::Helper(credential, _) | CredentialState::Netrc(credential) => {
+                HashMap::from([("Authorization".to_owned(), credential.authorization())])
+            }
+            CredentialState::Anonymous | CredentialState::Exhausted => HashMap::new(),
+        }
+    }
+
+    /// Switches to the `.netrc` login, returns `false` if there is none
+    fn use_netrc(&self, state: &mut CredentialState) -> bool {
+        match &self.netrc {
+            Some(credential) => {
+                debug!("Using .netrc login");
+                *state = CredentialState::Netrc(credential.clone());
+                true
+            }
+            None => {
+                *state = CredentialState::Exhausted;
+                false
+            }
+        }
+    }
+}
+
+impl AuthProvider for GitCredentialAuth {
+    fn headers<'a>(
+        &'a self,
+        _url: &'a Url,
+    ) -> BoxFuture<'a, Result<HashMap<String, String>, LFSError>> {
+        Box::pin(async { Ok(Self::headers_for(&*self.state.lock().await)) })
+    }
+
+    fn rejected<'a>(
+        &'a self,
+        _url: &'a Url,
+        headers: &'a HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<bool, LFSError>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            if Self::headers_for(&state) != *headers {
+                // a credential that was replaced already was denied, retry with the current one
+                return Ok(true);
+            }
+            match state.clone() {
+                CredentialState::Anonymous => {
+                    debug!("Access denied, asking credential helpers");
+                    match self.helpers.fill().await? {
+                        Some(credential) => {
+                            *state = CredentialState::Helper(credential, false);
+                            Ok(true)
+                        }
+                        None => Ok(self.use_netrc(&mut state)),
+                    }
+                }
+                CredentialState::Helper(credential, _) => {
+                    self.helpers.reject(&credential).await;
+                    Ok(self.use_netrc(&mut state))
+                }
+                CredentialState::Netrc(_) | CredentialState::Exhausted => {
+                    *state = CredentialState::Exhausted;
+                    Ok(false)
+                }
+            }
+        })
+    }
+
+    fn accepted<'a>(
+        &'a self,
+        _url: &'a Url,
+        headers: &'a HashMap<String, String>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            if let CredentialState::Helper(credential, false) = &*state {
+                if headers.get("Authorization") != Some(&credential.authorization()) {
+                    return;
+                }
+                self.helpers.approve(credential).await;
+                *state = CredentialState::Helper(credential.clone(), true);
+            }
+        })
+    }
+}
+
+/// Splits a helper command line into words, keeping whitespace inside single or double quotes.
+/// Backslashes are kept as they are, so Windows paths need no escaping.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Builds the command git would run for `helper`: `!` runs a shell snippet, absolute paths run as
+/// they are and everything else is `git credential-<helper>`. A helper naming an existing file is
+/// run even if its path contains unquoted spaces, like `C:\Program Files\Git\...\git-credential-manager.exe`.
+fn helper_command(helper: &str, operation: &str) -> Command {
+    let is_file = std::path::Path::new(helper).is_file();
+    let words = if is_file {
+        vec![helper.to_owned()]
+    } else {
+        split_words(helper.strip_prefix('!').unwrap_or(helper))
+    };
+    let absolute = words
+        .first()
+        .is_some_and(|program| std::path::Path::new(program).is_absolute());
+    if cfg!(windows) {
+        let mut words = words.into_iter();
+        let mut command = if helper.starts_with('!') || absolute {
+            Command::new(words.next().unwrap_or_default())
+        } else {
+            let mut command = Command::new("git");
+            command.arg(format!("credential-{}", words.next().unwrap_or_default()));
+            command
+        };
+        command.args(words).arg(operation);
+        command
+    } else {
+        let program = if let Some(snippet) = helper.strip_prefix('!') {
+            snippet.to_owned()
+        } else if is_file {
+            format!("'{}'", helper.replace('\'', r"'\''"))
+        } else if absolute {
+            helper.to_owned()
+        } else {
+            format!("git credential-{helper}")
+        };
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{program} \"$@\""))
+            .arg(&program)
+            .arg(operation);
+        command
+    }
+}
+
+async fn run_helper(helper: &str, operation: &str, input: &str) -> Result<String, LFSError> {
+    debug!("Running credential helper '{helper}' {operation}");
+    let helper_error =
+        |e: std::io::Error| LFSError::CredentialHelperFailed(format!("{helper}: {e}"));
+    let mut child = helper_command(helper, operation)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(helper_error)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input.as_bytes())
+            .await
+            .map_err(helper_error)?;
+    }
+    let output = child.wait_with_output().await.map_err(helper_error)?;
+    if !output.status.success() {
+        return Err(LFSError::CredentialHelperFailed(format!(
+            "{helper} exited with {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn falls_back_to_netrc_after_helpers() {
+        let url = Url::parse("https://netrc.example.com/repo").unwrap();
+        let helpers = CredentialHelpers::from_config(&GitConfig::default(), &url);
+        let login = NetrcLogin {
+            login: Some("ci".to_owned()),
+            password: Some("token".to_owned()),
+        };
+        let auth = GitCredentialAuth::new(&helpers, Some(&login));
+        let anonymous = auth.headers(&url).await.unwrap();
+        assert!(anonymous.is_empty());
+        assert!(auth.rejected(&url, &anonymous).await.unwrap());
+        let netrc = auth.headers(&url).await.unwrap();
+        assert_eq!(
+            netrc.get("Authorization"),
+            Some(&"Basic Y2k6dG9rZW4=".to_owned())
+        );
+        // a concurrent request denied without credentials only retries with the netrc login
+        assert!(auth.rejected(&url, &anonymous).await.unwrap());
+        assert_eq!(auth.headers(&url).await.unwrap(), netrc);
+        assert!(!auth.rejected(&url, &netrc).await.unwrap());
+        assert!(auth.headers(&url).await.unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn fill_approve_and_reject_with_fake_helper() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let log = dir.path().join("calls.log");
+        let script = dir.path().join("fake-helper");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$1\" >> {log}\ncat >> {log}\n\
+                 if [ \"$1\" = get ]; then echo username=jane; echo password=secret; fi\n",
+                log = log.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = GitConfig::parse(&format!(
+            "[credential]\n\thelper = cache\n\thelper =\n\
+             [credential \"https://lfs.example.com:8443\"]\n\thelper = {}\n\tuseHttpPath = true\n",
+            script.to_string_lossy()
+        ))
+        .unwrap();
+        let url = Url::parse("https://lfs.example.com:8443/repo/info/lfs").unwrap();
+        let helpers = CredentialHelpers::from_config(&config, &url);
+        assert!(helpers.cached().is_none());
+
+        let credential = helpers
+            .fill()
+            .await
+            .unwrap()
+            .expect("fake helper gave no credentials");
+        assert_eq!(credential.username.as_deref(), Some("jane"));
+        assert_eq!(credential.authorization(), "Basic amFuZTpzZWNyZXQ=");
+        helpers.approve(&credential).await;
+        assert_eq!(helpers.cached(), Some(credential.clone()));
+        helpers.reject(&credential).await;
+        assert!(helpers.cached().is_none());
+
+        let calls = std::fs::read_to_string(&log).unwrap();
+        let request = "protocol=https\nhost=lfs.example.com:8443\npath=repo/info/lfs\n";
+        let full = format!("{request}username=jane\npassword=secret\n");
+        assert_eq!(
+            calls,
+            format!("get\n{request}\nstore\n{full}\nerase\n{full}\n")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_denials_ask_helpers_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let log = dir.path().join("calls.log");
+        let script = dir.path().join("fake-helper");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$1\" >> {}\ncat > /dev/null\nsleep 0.1\n\
+                 echo username=jane; echo password=secret\n",
+                log.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = GitConfig::parse(&format!(
+            "[credential]\n\thelper = {}\n",
+            script.to_string_lossy()
+        ))
+        .unwrap();
+        let url = Url::parse("https://concurrent.example.com/repo").unwrap();
+        let auth = GitCredentialAuth::new(&CredentialHelpers::from_config(&config, &url), None);
+        let anonymous = auth.headers(&url).await.unwrap();
+        let (first, second) = tokio::join!(
+            auth.rejected(&url, &anonymous),
+            auth.rejected(&url, &anonymous)
+        );
+        assert!(first.unwrap() && second.unwrap());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "get\n");
+        assert_eq!(
+            auth.headers(&url).await.unwrap().get("Authorization"),
+            Some(&"Basic amFuZTpzZWNyZXQ=".to_owned())
+        );
+    }
+
+    #[test]
+    fn helper_words_keep_quoted_whitespace() {
+        assert_eq!(
+            split_words(r#""C:\Program Files\Git\helper.exe" --flag 'a b'  c"#),
+            vec![r"C:\Program Files\Git\helper.exe", "--flag", "a b", "c"]
+        );
+        assert_eq!(split_words("store --file ''"), vec!["store", "--file", ""]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn helper_path_with_spaces() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let folder = dir.path().join("Program Files");
+        std::fs::create_dir(&folder).unwrap();
+        let script = folder.join("credential helper");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncat > /dev/null\necho username=\"$1\"\necho password=secret\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let output = run_helper(&script.to_string_lossy(), "get", "\n")
+            .await
+            .unwrap();
+        assert_eq!(output, "username=get\npassword=secret\n");
+    }
+}
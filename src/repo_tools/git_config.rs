@@ -0,0 +1,763 @@
+use crate::prelude::*;
+use glob::{MatchOptions, Pattern};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use tokio::fs::read_to_string;
+use tracing::debug;
+use url::Url;
+use vg_errortools::{fat_io_wrap_tokio, FatIOError};
+
+/// git refuses to follow more nested includes than this
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// A single `key = value` line of a git config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    /// Section name, lowercased
+    pub section: String,
+    /// Subsection name, case-sensitive unless the legacy `[section.subsection]` syntax was used
+    pub subsection: Option<String>,
+    /// Key name, lowercased
+    pub key: String,
+    /// The value, `None` for a bare key which git treats as boolean `true`
+    pub value: Option<String>,
+}
+
+impl ConfigEntry {
+    fn matches(&self, section: &str, subsection: Option<&str>, key: &str) -> bool {
+        self.section == section && self.subsection.as_deref() == subsection && self.key == key
+    }
+}
+
+/// Parsed git config, keeping all entries in file order so multi-valued keys and
+/// "last one wins" lookups behave like git
+#[derive(Debug, Clone, Default)]
+pub struct GitConfig {
+    entries: Vec<ConfigEntry>,
+}
+
+/// Splits `section.subsection.key` into its parts, lowercasing section and key
+fn split_name(name: &str) -> Option<(String, Option<&str>, String)> {
+    let (section, rest) = name.split_once('.')?;
+    let (subsection, key) = match rest.rsplit_once('.') {
+        Some((subsection, key)) => (Some(subsection), key),
+        None => (None, rest),
+    };
+    Some((section.to_lowercase(), subsection, key.to_lowercase()))
+}
+
+/// Checks whether the `<section>.<url>.<key>` subsection `pattern` applies to `url` following git's
+/// urlmatch rules: same scheme, host (`*` matches one label), port and user if given, and a path prefix
+/// ending at a `/`. Returns how specific the match is, longer paths and an explicit user rank higher.
+pub fn url_match(pattern: &str, url: &Url) -> Option<(usize, bool)> {
+    let (scheme, rest) = pattern.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case(url.scheme()) {
+        return None;
+    }
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+    let (user, host_port) = match authority.rsplit_once('@') {
+        Some((user, host_port)) => (Some(user), host_port),
+        None => (None, authority),
+    };
+    if user.is_some_and(|user| user != url.username()) {
+        return None;
+    }
+    let (host, port) = match host_port
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+    {
+        Some((host, port)) => (host, Some(port)),
+        None => (host_port, None),
+    };
+    // a pattern without port means the default port of its scheme, not any port
+    if port.or_else(|| default_port(scheme)) != url.port_or_known_default() {
+        return None;
+    }
+    let url_host = url.host_str()?;
+    let host_labels: Vec<_> = host.split('.').collect();
+    let url_labels: Vec<_> = url_host.split('.').collect();
+    if host_labels.len() != url_labels.len()
+        || !host_labels
+            .iter()
+            .zip(&url_labels)
+            .all(|(label, url_label)| *label == "*" || label.eq_ignore_ascii_case(url_label))
+    {
+        return None;
+    }
+    let path = path.trim_end_matches('/');
+    let url_path = url.path();
+    if !path.is_empty()
+        && url_path != path
+        && !url_path
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.starts_with('/'))
+    {
+        return None;
+    }
+    Some((path.len(), user.is_some()))
+}
+
+/// The port `scheme` uses when urls leave it out
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme.to_lowercase().as_str() {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        _ => None,
+    }
+}
+
+/// Parses a git boolean (`true`/`yes`/`on`/`1`, `false`/`no`/`off`/`0`/empty)
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" | "" => Some(false),
+        number => number.parse::<i64>().ok().map(|n| n != 0),
+    }
+}
+
+impl GitConfig {
+    /// Loads the configuration git sees for the repository at `git_dir`: system, global and
+    /// the repository's own config in `common_dir` in that order of precedence, including
+    /// `include.path` and `includeIf "gitdir:..."` files. With `extensions.worktreeConfig` the
+    /// worktree's `config.worktree` comes last.
+    pub async fn load(
+        common_dir: impl AsRef<Path>,
+        git_dir: impl AsRef<Path>,
+    ) -> Result<Self, LFSError> {
+        Self::load_with_env(common_dir.as_ref(), git_dir.as_ref(), |name| {
+            std::env::var(name).ok()
+        })
+        .await
+    }
+
+    async fn load_with_env(
+        common_dir: &Path,
+        git_dir: &Path,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, LFSError> {
+        let home = var("HOME")
+            .or_else(|| var("USERPROFILE"))
+            .filter(|home| !home.is_empty())
+            .map(PathBuf::from);
+
+        let mut files = Vec::new();
+        let no_system = var("GIT_CONFIG_NOSYSTEM")
+            .and_then(|value| parse_bool(&value))
+            .unwrap_or(false);
+        if !no_system {
+            files.push(
+                var("GIT_CONFIG_SYSTEM")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from("/etc/gitconfig")),
+            );
+        }
+        if let Some(global) = var("GIT_CONFIG_GLOBAL") {
+            files.push(PathBuf::from(global));
+        } else {
+            let xdg_config = var("XDG_CONFIG_HOME")
+                .filter(|xdg| !xdg.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| home.as_ref().map(|home| home.join(".config")));
+            files.extend(xdg_config.map(|xdg| xdg.join("git").join("config")));
+            files.extend(home.as_ref().map(|home| home.join(".gitconfig")));
+        }
+        files.push(common_dir.join("config"));
+
+        let loader = Loader {
+            git_dir: std::fs::canonicalize(git_dir).unwrap_or_else(|_| git_dir.to_path_buf()),
+            home,
+        };
+        let mut entries = Vec::new();
+        for file in files {
+            loader.load_file(&file, 0, &mut entries).await?;
+        }
+        let mut config = GitConfig { entries };
+        if config
+            .get("extensions.worktreeconfig")
+            .and_then(parse_bool)
+            .unwrap_or(false)
+        {
+            loader
+                .load_file(&git_dir.join("config.worktree"), 0, &mut config.entries)
+                .await?;
+        }
+        Ok(config)
+    }
+
+    /// Reads and parses the config file at `path`
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Self, LFSError> {
+        let contents = fat_io_wrap_tokio(path.as_ref(), read_to_string).await?;
+        Self::parse(&contents).map_err(|e| match e {
+            LFSError::InvalidGitConfig(message) => LFSError::InvalidGitConfig(format!(
+                "{}: {message}",
+                path.as_ref().to_string_lossy()
+            )),
+            e => e,
+        })
+    }
+
+    /// Parses the contents of a git config file
+    pub fn parse(input: &str) -> Result<Self, LFSError> {
+        Parser::new(input).parse()
+    }
+
+    /// Returns the last value of `name` (e.g. `remote.origin.url`), like `git config --get`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).pop()
+    }
+
+    /// Returns all values of `name` in the order they appear, like `git config --get-all`
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        let Some((section, subsection, key)) = split_name(name) else {
+            return Vec::new();
+        };
+        self.entries
+            .iter()
+            .filter(|entry| entry.matches(&section, subsection, &key))
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    /// Returns all values of `section.key` and `section.<url>.key` whose url matches `url`, in the
+    /// order they appear, like `git config --get-urlmatch --get-all`
+    pub fn get_all_for_url(&self, section: &str, key: &str, url: &Url) -> Vec<&str> {
+        let section = section.to_lowercase();
+        let key = key.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|entry| entry.section == section && entry.key == key)
+            .filter(|entry| {
+                entry
+                    .subsection
+                    .as_deref()
+                    .is_none_or(|pattern| url_match(pattern, url).is_some())
+            })
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    /// Returns the value of `section.key` or of the most specific `section.<url>.key` matching `url`.
+    /// Among equally specific entries the last one wins.
+    pub fn get_for_url(&self, section: &str, key: &str, url: &Url) -> Option<&str> {
+        let section = section.to_lowercase();
+        let key = key.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|entry| entry.section == section && entry.key == key)
+            .filter_map(|entry| {
+                let specificity = match entry.subsection.as_deref() {
+                    Some(pattern) => Some(url_match(pattern, url)?),
+                    None => None,
+                };
+                Some((specificity, entry.value.as_deref().unwrap_or_default()))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the distinct subsection names of `section` in order of first appearance
+    pub fn subsections(&self, section: &str) -> Vec<&str> {
+        let section = section.to_lowercase();
+        let mut result: Vec<&str> = Vec::new();
+        for entry in self.entries.iter().filter(|e| e.section == section) {
+            if let Some(subsection) = entry.subsection.as_deref() {
+                if !result.contains(&subsection) {
+                    result.push(subsection);
+                }
+            }
+        }
+        result
+    }
+}
+
+struct Loader {
+    git_dir: PathBuf,
+    home: Option<PathBuf>,
+}
+
+impl Loader {
+    /// Appends the entries of `path` to `entries`, splicing in included files where they are referenced.
+    /// Missing files are skipped like git does.
+    async fn load_file(
+        &self,
+        path: &Path,
+        depth: usize,
+        entries: &mut Vec<ConfigEntry>,
+    ) -> Result<(), LFSError> {
+        let contents = match read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(LFSError::FatFileIOError(FatIOError::from_std_io_err(
+                    e,
+                    path.to_path_buf(),
+                )))
+            }
+        };
+        debug!("Read git config file in {}", path.to_string_lossy());
+        let config = GitConfig::parse(&contents).map_err(|e| match e {
+            LFSError::InvalidGitConfig(message) => {
+                LFSError::InvalidGitConfig(format!("{}: {message}", path.to_string_lossy()))
+            }
+            e => e,
+        })?;
+
+        for entry in config.entries {
+            let include = match (entry.section.as_str(), entry.subsection.as_deref()) {
+                ("include", None) => entry.key == "path",
+                ("includeif", Some(condition)) => {
+                    entry.key == "path" && self.condition_matches(condition, path)
+                }
+                _ => false,
+            };
+            let include_path = entry
+                .value
+                .as_deref()
+                .filter(|_| include)
+                .map(|value| self.resolve_path(value, path));
+            entries.push(entry);
+
+            if let Some(include_path) = include_path {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(LFSError::InvalidGitConfig(format!(
+                        "{}: exceeded maximum include depth",
+                        path.to_string_lossy()
+                    )));
+                }
+                Box::pin(self.load_file(&include_path, depth + 1, entries)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands `~/` and makes relative paths relative to the including file
+    fn resolve_path(&self, value: &str, config_path: &Path) -> PathBuf {
+        match (value.strip_prefix("~/"), &self.home) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => config_path
+                .parent()
+                .map(|parent| parent.join(value))
+                .unwrap_or_else(|| PathBuf::from(value)),
+        }
+    }
+
+    fn condition_matches(&self, condition: &str, config_path: &Path) -> bool {
+        let (pattern, case_sensitive) = if let Some(pattern) = condition.strip_prefix("gitdir:") {
+            (pattern, true)
+        } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+            (pattern, false)
+        } else {
+            debug!("Ignoring unsupported includeIf condition '{condition}'");
+            return false;
+        };
+
+        let mut pattern = if let Some(rest) = pattern.strip_prefix("./") {
+            let base = config_path.parent().unwrap_or(Path::new(""));
+            base.join(rest).to_string_lossy().to_string()
+        } else if pattern.starts_with("~/") {
+            self.resolve_path(pattern, config_path)
+                .to_string_lossy()
+                .to_string()
+        } else if Path::new(pattern).is_absolute() {
+            pattern.to_owned()
+        } else {
+            format!("**/{pattern}")
+        };
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        }
+
+        let options = MatchOptions {
+            case_sensitive,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        Pattern::new(&pattern)
+            .map(|pattern| pattern.matches_path_with(&self.git_dir, options))
+            .unwrap_or(false)
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    section: Option<(String, Option<String>)>,
+    entries: Vec<ConfigEntry>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Parser {
+            chars: input.chars().peekable(),
+            line: 1,
+            section: None,
+            entries: Vec::new(),
+        }
+    }
+
+    fn error(&self, message: &str) -> LFSError {
+        LFSError::InvalidGitConfig(format!("line {}: {message}", self.line))
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\t' | '\r')) {
+            self.next();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    fn parse(mut self) -> Result<GitConfig, LFSError> {
+        // tolerate a UTF-8 BOM like git does
+        if self.chars.peek() == Some(&'\u{feff}') {
+            self.next();
+        }
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                c if c.is_whitespace() => {
+                    self.next();
+                }
+                '#' | ';' => self.skip_line(),
+                '[' => {
+                    self.next();
+                    self.section = Some(self.parse_section_header()?);
+                }
+                c if c.is_ascii_alphabetic() => self.parse_entry()?,
+                _ => return Err(self.error("unexpected character")),
+            }
+        }
+        Ok(GitConfig {
+            entries: self.entries,
+        })
+    }
+
+    fn parse_section_header(&mut self) -> Result<(String, Option<String>), LFSError> {
+        let mut name = String::new();
+        loop {
+            match self.next() {
+                Some(']') => {
+                    if name.is_empty() {
+                        return Err(self.error("empty section name"));
+                    }
+                    // deprecated [section.subsection] syntax, subsection is case-insensitive
+                    return Ok(match name.split_once('.') {
+                        Some((section, subsection)) => {
+                            (section.to_owned(), Some(subsection.to_owned()))
+                        }
+                        None => (name, None),
+                    });
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '.' => {
+                    name.push(c.to_ascii_lowercase())
+                }
+                Some(' ' | '\t') => break,
+                _ => return Err(self.error("invalid section header")),
+            }
+        }
+
+        self.skip_blanks();
+        if self.next() != Some('"') {
+            return Err(self.error("expected quoted subsection name"));
+        }
+        let mut subsection = String::new();
+        loop {
+            match self.next() {
+                Some('"') => break,
+                Some('\\') => match self.next() {
+                    Some('\n') | None => return Err(self.error("unterminated subsection name")),
+                    Some(c) => subsection.push(c),
+                },
+                Some('\n') | None => return Err(self.error("unterminated subsection name")),
+                Some(c) => subsection.push(c),
+            }
+        }
+        if self.next() != Some(']') {
+            return Err(self.error("expected ']' after subsection name"));
+        }
+        Ok((name, Some(subsection)))
+    }
+
+    fn parse_entry(&mut self) -> Result<(), LFSError> {
+        let mut key = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                key.push(c.to_ascii_lowercase());
+                self.next();
+            } else {
+                break;
+            }
+        }
+        let (section, subsection) = self
+            .section
+            .clone()
+            .ok_or_else(|| self.error("key outside of any section"))?;
+
+        self.skip_blanks();
+        let value = match self.chars.peek() {
+            Some('=') => {
+                self.next();
+                Some(self.parse_value()?)
+            }
+            Some('\n') | None => None,
+            Some('#' | ';') => {
+                self.skip_line();
+                None
+            }
+            _ => return Err(self.error("invalid key name")),
+        };
+
+        self.entries.push(ConfigEntry {
+            section,
+            subsection,
+            key,
+            value,
+        });
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<String, LFSError> {
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut pending_spaces = 0;
+        self.skip_blanks();
+        loop {
+            let c = match self.next() {
+                None | Some('\n') if quoted => return Err(self.error("unterminated quote")),
+                None | Some('\n') => break,
+                Some(c) => c,
+            };
+            match c {
+                '#' | ';' if !quoted => {
+                    self.skip_line();
+                    break;
+                }
+                c if !quoted && c.is_whitespace() => {
+                    // inner whitespace is kept, trailing whitespace is dropped
+                    pending_spaces += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            value.extend(std::iter::repeat_n(' ', pending_spaces));
+            pending_spaces = 0;
+            match c {
+                '"' => quoted = !quoted,
+                '\\' => match self.next() {
+                    Some('\n') => {}
+                    Some('\r') if self.chars.peek() == Some(&'\n') => {
+                        self.next();
+                    }
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => value.push('\u{8}'),
+                    Some(c @ ('\\' | '"')) => value.push(c),
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                c => value.push(c),
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# comment
+[core]
+	bare = false
+	logallrefupdates
+[remote "origin"]
+	url = git@github.com:VolumeGraphics/lfspull.git
+	pushurl = ssh://git@example.com/push.git
+	fetch = +refs/heads/*:refs/remotes/origin/*
+[remote "mirror"]
+	url = https://mirror.example.com/lfspull.git ; trailing comment
+[url "https://mirror.example.com/"]
+	insteadOf = https://github.com/
+	insteadOf = git@github.com:
+[Branch.Main]
+	remote = mirror
+[lfs]
+	storage="/some path/with \"quotes\"\tand tab" # comment
+	url = https://lfs.example.com/\
+objects
+"#;
+
+    #[test]
+    fn parses_sections_and_subsections() {
+        let config = GitConfig::parse(CONFIG).expect("could not parse config");
+        assert_eq!(
+            config.get("remote.origin.url"),
+            Some("git@github.com:VolumeGraphics/lfspull.git")
+        );
+        assert_eq!(
+            config.get("remote.mirror.url"),
+            Some("https://mirror.example.com/lfspull.git")
+        );
+        assert_eq!(config.get("Core.Bare"), Some("false"));
+        assert_eq!(config.get("core.logallrefupdates"), Some(""));
+        assert_eq!(config.get("branch.main.remote"), Some("mirror"));
+        assert_eq!(config.subsections("remote"), vec!["origin", "mirror"]);
+    }
+
+    #[test]
+    fn parses_multi_values_and_escapes() {
+        let config = GitConfig::parse(CONFIG).expect("could not parse config");
+        assert_eq!(
+            config.get_all("url.https://mirror.example.com/.insteadof"),
+            vec!["https://github.com/", "git@github.com:"]
+        );
+        assert_eq!(
+            config.get("lfs.storage"),
+            Some("/some path/with \"quotes\"\tand tab")
+        );
+        assert_eq!(
+            config.get("lfs.url"),
+            Some("https://lfs.example.com/objects")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn loads_layered_config_with_includes() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let git_dir = root.join("work").join("repo").join(".git");
+        std::fs::create_dir_all(&git_dir).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(root.join(name), contents).unwrap();
+        write(
+            "system",
+            "[lfs]\n\tstorage = system\n[http]\n\tsslVerify = false\n",
+        );
+        write(
+            "global",
+            "[lfs]\n\tstorage = global\n[include]\n\tpath = included\n\
+             [includeIf \"gitdir:work/\"]\n\tpath = work.inc\n\
+             [includeIf \"gitdir:elsewhere/\"]\n\tpath = elsewhere.inc\n",
+        );
+        write("included", "[http]\n\textraHeader = from-include\n");
+        write("work.inc", "[user]\n\temail = work@example.com\n");
+        write("elsewhere.inc", "[user]\n\temail = elsewhere@example.com\n");
+        std::fs::write(git_dir.join("config"), "[core]\n\tbare = false\n").unwrap();
+
+        let env = |name: &str| match name {
+            "GIT_CONFIG_SYSTEM" => Some(root.join("system").to_string_lossy().to_string()),
+            "GIT_CONFIG_GLOBAL" => Some(root.join("global").to_string_lossy().to_string()),
+            _ => None,
+        };
+        let config = GitConfig::load_with_env(&git_dir, &git_dir, env)
+            .await
+            .unwrap();
+        assert_eq!(config.get("lfs.storage"), Some("global"));
+        assert_eq!(config.get("http.sslverify"), Some("false"));
+        assert_eq!(config.get("http.extraheader"), Some("from-include"));
+        assert_eq!(config.get("user.email"), Some("work@example.com"));
+        assert_eq!(config.get("core.bare"), Some("false"));
+
+        std::fs::write(git_dir.join("config"), "[lfs]\n\tstorage = local\n").unwrap();
+        let env = |name: &str| match name {
+            "GIT_CONFIG_NOSYSTEM" => Some("1".to_owned()),
+            "GIT_CONFIG_SYSTEM" => Some(root.join("system").to_string_lossy().to_string()),
+            "HOME" => Some(root.join("home").to_string_lossy().to_string()),
+            _ => None,
+        };
+        let config = GitConfig::load_with_env(&git_dir, &git_dir, env)
+            .await
+            .unwrap();
+        assert_eq!(config.get("lfs.storage"), Some("local"));
+        assert_eq!(config.get("http.sslverify"), None);
+
+        let worktree_dir = git_dir.join("worktrees").join("feature");
+        std::fs::create_dir_all(&worktree_dir).unwrap();
+        std::fs::write(
+            worktree_dir.join("config.worktree"),
+            "[lfs]\n\tstorage = worktree\n",
+        )
+        .unwrap();
+        let config = GitConfig::load_with_env(&git_dir, &worktree_dir, env)
+            .await
+            .unwrap();
+        assert_eq!(config.get("lfs.storage"), Some("local"));
+        std::fs::write(
+            git_dir.join("config"),
+            "[extensions]\n\tworktreeConfig = true\n[lfs]\n\tstorage = local\n",
+        )
+        .unwrap();
+        let config = GitConfig::load_with_env(&git_dir, &worktree_dir, env)
+            .await
+            .unwrap();
+        assert_eq!(config.get("lfs.storage"), Some("worktree"));
+    }
+
+    #[test]
+    fn matches_urls_like_git() {
+        let url = Url::parse("https://user@git.example.com/group/repo.git/info/lfs").unwrap();
+        assert_eq!(url_match("https://git.example.com", &url), Some((0, false)));
+        assert_eq!(url_match("https://*.example.com/", &url), Some((0, false)));
+        assert_eq!(
+            url_match("https://user@git.example.com:443/group", &url),
+            Some((6, true))
+        );
+        assert_eq!(url_match("https://git.example.com/gr", &url), None);
+        assert_eq!(url_match("http://git.example.com", &url), None);
+        assert_eq!(url_match("https://other@git.example.com", &url), None);
+        assert_eq!(url_match("https://*.com", &url), None);
+        assert_eq!(url_match("https://git.example.com:8443", &url), None);
+        let other_port = Url::parse("https://git.example.com:8443/group/repo.git").unwrap();
+        assert_eq!(url_match("https://git.example.com", &other_port), None);
+        assert_eq!(
+            url_match("https://git.example.com:8443", &other_port),
+            Some((0, false))
+        );
+
+        let config = GitConfig::parse(
+            "[credential]\n\thelper = store\n\
+             [credential \"https://git.example.com/group\"]\n\thelper = group\n\
+             [credential \"https://other.example.com\"]\n\thelper = other\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_all_for_url("credential", "helper", &url),
+            vec!["store", "group"]
+        );
+
+        let config = GitConfig::parse(
+            "[http \"https://git.example.com/group\"]\n\tsslVerify = false\n\
+             [http]\n\tsslVerify = true\n\
+             [http \"https://*.example.com\"]\n\tsslVerify = true\n",
+        )
+        .unwrap();
+        assert_eq!(config.get_for_url("http", "sslverify", &url), Some("false"));
+        let url = Url::parse("https://git.example.com/other").unwrap();
+        assert_eq!(config.get_for_url("http", "sslVerify", &url), Some("true"));
+        assert_eq!(config.get_for_url("http", "sslCAInfo", &url), None);
+    }
+
+    #[test]
+    fn rejects_malformed_config() {
+        assert!(GitConfig::parse("[remote \"origin]\n").is_err());
+        assert!(GitConfig::parse("url = outside\n").is_err());
+        assert!(GitConfig::parse("[lfs]\nstorage = \"unterminated\n").is_err());
+    }
+}
@@ -1,254 +1,688 @@
 use crate::prelude::*;
+mod auth;
+mod client;
+mod credentials;
+mod endpoint;
+mod git_config;
+mod netrc;
 mod primitives;
+mod proxy;
+mod repository;
+mod ssh_auth;
+mod ssh_transfer;
+mod tls;
 
-use futures_util::TryFutureExt;
+pub use auth::{AuthProvider, BasicAuth, BearerAuth, HeaderAuth, NoAuth, TokenCommandAuth};
+pub use client::ConnectionOptions;
+use client::HttpClients;
+use endpoint::LfsEndpoint;
+use futures_util::TryStreamExt;
+use git_config::GitConfig;
 use glob::glob;
-use primitives::get_repo_root;
+use repository::Repository;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+pub use tls::TlsOptions;
 use tokio::fs;
-use tokio::fs::read_to_string;
 use tracing::{debug, error, info, warn};
-use url::Url;
 use vg_errortools::{fat_io_wrap_tokio, FatIOError};
 
-async fn get_remote_url_from_file(git_file: impl AsRef<Path>) -> Result<String, LFSError> {
-    let file_buffer = fat_io_wrap_tokio(git_file, read_to_string).await?;
-    let remote_url = file_buffer
-        .lines()
-        .find(|&line| line.contains("url"))
-        .as_ref()
-        .ok_or(LFSError::InvalidFormat(
-            ".git/config contains no remote url",
-        ))?
-        .split('=')
-        .next_back()
-        .as_ref()
-        .ok_or(LFSError::InvalidFormat(".git/config url line malformed"))?
-        .trim();
-    Ok(remote_url.to_owned())
+/// Settings for pulling files, shared by [`pull_file`] and [`glob_recurse_pull_directory`]
+#[derive(Debug, Clone)]
+pub struct PullOptions {
+    /// Max number of download attempts when the http request fails
+    pub max_retry: u32,
+    /// Bytes used to create a randomized named temp file, this might be important if using parallelism
+    pub randomizer_bytes: Option<usize>,
+    /// Timeout in seconds for the download: `None` calculates it from the object size, `Some(0)` disables it
+    pub timeout: Option<u64>,
+    /// The git remote to pull from, defaults to the upstream remote of the current branch or `origin`
+    pub remote: Option<String>,
+    /// Allow plain http LFS endpoints, otherwise http remotes are upgraded to https
+    pub allow_http: bool,
+    /// The ref sent to the LFS server, which may use it to check branch permissions
+    pub git_ref: BatchRef,
+    /// CA certificates, client certificate and verification for the LFS server, overriding git config
+    pub tls: TlsOptions,
+    /// Connection pooling, keep-alive and HTTP/2 settings of the HTTP client shared by all downloads
+    pub connection: ConnectionOptions,
+    /// Objects requested per batch API call when pulling many files, git-lfs uses 100
+    pub batch_size: usize,
+    /// Objects downloaded at the same time, `None` uses `lfs.concurrenttransfers` or 8 like git-lfs
+    pub concurrency: Option<usize>,
 }
 
-async fn get_real_repo_root<P: AsRef<Path>>(repo_path: P) -> Result<PathBuf, LFSError> {
-    let git_path = repo_path.as_ref().join(".git");
-    let real_git_path = if repo_path.as_ref().join(".git").is_file() {
-        //worktree case
-        let worktree_file_contents = fat_io_wrap_tokio(git_path, read_to_string).await?;
-        let worktree_path = worktree_file_contents
-            .split(':')
-            .find(|c| c.contains(".git"))
-            .expect("Could not resolve original repo .git/config file from worktree .git file")
-            .trim();
-        get_repo_root(worktree_path)
-            .await
-            .expect("Found worktree, but couldn't resolve root-repo")
-    } else if git_path.is_dir() {
-        //git main copy
-        git_path
-            .parent()
-            .expect("Git path has no parent")
-            .to_owned()
-    } else {
-        //no .git in repo_root - bad
-        return Err(LFSError::DirectoryTraversalError(
-            "Could not find .git file or folder in directory structure".to_owned(),
-        ));
+impl Default for PullOptions {
+    fn default() -> Self {
+        PullOptions {
+            max_retry: 3,
+            randomizer_bytes: None,
+            timeout: None,
+            remote: None,
+            allow_http: false,
+            git_ref: BatchRef::default(),
+            tls: TlsOptions::default(),
+            connection: ConnectionOptions::default(),
+            batch_size: 100,
+            concurrency: None,
+        }
+    }
+}
+
+/// Downloads at the same time without `lfs.concurrenttransfers`, the git-lfs default
+const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Which ref is sent with batch requests
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BatchRef {
+    /// The upstream `branch.<name>.merge` of the checked out branch, or of a branch pointing at a detached `HEAD`
+    #[default]
+    FromHead,
+    /// A fixed ref, e.g. `refs/heads/main`
+    Name(String),
+    /// Send no ref at all
+    Omit,
+}
+
+/// How objects are fetched from the LFS server
+enum Transfer {
+    /// The batch API over http(s)
+    Http {
+        endpoint: Box<LfsEndpoint>,
+        /// The credential helpers to ask for a login without explicit [`AuthProvider`]
+        helpers: Box<credentials::CredentialHelpers>,
+        /// The `.netrc` login for the endpoint host, tried after the helpers
+        netrc: Option<netrc::NetrcLogin>,
+        /// `git-lfs-authenticate` for ssh remotes, used without explicit [`AuthProvider`]
+        ssh_auth: Option<Box<ssh_auth::SshAuth>>,
+        /// How many objects are downloaded at the same time
+        concurrency: usize,
+    },
+    /// The `git-lfs-transfer` protocol over ssh
+    Ssh(ssh_transfer::SshTransfer),
+}
+
+/// Resolves how to reach the LFS server of `repository` from git config and `.lfsconfig`
+async fn get_transfer(
+    repository: &Repository,
+    options: &PullOptions,
+) -> Result<Transfer, LFSError> {
+    let config = repository.config().await?;
+    let lfs_config_file = repository
+        .work_tree
+        .as_ref()
+        .map(|work_tree| work_tree.join(".lfsconfig"))
+        .filter(|lfs_config_file| lfs_config_file.is_file());
+    let lfs_config = match lfs_config_file {
+        Some(lfs_config_file) => GitConfig::from_file(lfs_config_file).await?,
+        None => GitConfig::default(),
+    };
+
+    let remote = match options.remote.as_deref() {
+        Some(remote) => remote.to_owned(),
+        None => {
+            let branch = repository.head_branch().await;
+            endpoint::select_remote_name(&config, branch.as_deref())
+        }
     };
+    debug!("Using remote '{remote}'");
+    let git_ref = match &options.git_ref {
+        BatchRef::FromHead => repository.upstream_ref(&config).await,
+        BatchRef::Name(name) => Some(name.clone()),
+        BatchRef::Omit => None,
+    };
+    debug!("Using ref {git_ref:?}");
 
-    Ok(real_git_path)
+    let mut lfs_endpoint =
+        endpoint::resolve_endpoint(&config, &lfs_config, &remote, options.allow_http)?;
+    lfs_endpoint.git_ref = git_ref.clone();
+    let mut ssh_auth = None;
+    if let Some(ssh_remote) = &lfs_endpoint.ssh {
+        let ssh = ssh_auth::SshCommand::from_config(&config);
+        let mode = ssh_transfer::SshTransferMode::from_config(&config);
+        if mode != ssh_transfer::SshTransferMode::Never {
+            let transfer = ssh_transfer::SshTransfer::new(ssh.clone(), ssh_remote.clone(), git_ref);
+            if mode == ssh_transfer::SshTransferMode::Always || transfer.is_supported().await {
+                debug!("Using git-lfs-transfer over ssh");
+                return Ok(Transfer::Ssh(transfer));
+            }
+        }
+        match ssh_auth::authenticate(&ssh, ssh_remote, "download").await {
+            Ok(response) => {
+                if let Some(href) = response.href {
+                    lfs_endpoint.url = href;
+                }
+                // the headers expire, so they are requested through the auth provider every time
+                ssh_auth = Some(Box::new(ssh_auth::SshAuth::new(ssh, ssh_remote.clone())));
+            }
+            Err(e) => warn!("{e}, falling back to {}", lfs_endpoint.url),
+        }
+    }
+    let url = url::Url::parse(&lfs_endpoint.url)?;
+    lfs_endpoint
+        .headers
+        .extend(endpoint::extra_headers(&config, &url));
+    lfs_endpoint.tls = options.tls.with_config(&config, &url);
+    lfs_endpoint.proxy = proxy::ProxySettings::from_config(&config, &url);
+    let netrc = match netrc::Netrc::load().await {
+        Ok(netrc) => netrc.lookup(url.host_str().unwrap_or_default()).cloned(),
+        Err(e) => {
+            warn!("Could not read .netrc: {e}");
+            None
+        }
+    };
+    let concurrency = options
+        .concurrency
+        .or_else(|| {
+            let key = "lfs.concurrenttransfers";
+            config
+                .get(key)
+                .or_else(|| lfs_config.get(key))?
+                .parse()
+                .ok()
+        })
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .max(1);
+    Ok(Transfer::Http {
+        helpers: Box::new(credentials::CredentialHelpers::from_config(&config, &url)),
+        concurrency,
+        netrc,
+        ssh_auth,
+        endpoint: Box::new(lfs_endpoint),
+    })
 }
 
-async fn get_remote_url<P: AsRef<Path>>(repo_path: P) -> Result<String, LFSError> {
-    let config_file = get_real_repo_root(repo_path.as_ref())
-        .await?
-        .join(".git")
-        .join("config");
+/// Downloads over http, retrying as long as `auth` wants to after the server denied access
+async fn download_http(
+    metadata: &primitives::MetaData,
+    lfs_endpoint: &LfsEndpoint,
+    clients: &HttpClients,
+    auth: &dyn AuthProvider,
+    options: &PullOptions,
+    temp_dir: &Path,
+) -> Result<tempfile::NamedTempFile, LFSError> {
+    let url = url::Url::parse(&lfs_endpoint.url)?;
+    let client = clients.get(lfs_endpoint).await?;
+    loop {
+        let headers = auth.headers(&url).await?;
+        let result = primitives::download_file(
+            metadata,
+            lfs_endpoint,
+            &client,
+            &headers,
+            options,
+            Some(temp_dir),
+        )
+        .await;
+        match result {
+            Ok(temp_file) => {
+                auth.accepted(&url, &headers).await;
+                return Ok(temp_file);
+            }
+            Err(LFSError::AccessDenied) => {
+                if !auth.rejected(&url, &headers).await? {
+                    return Err(LFSError::AccessDenied);
+                }
+                debug!("Retrying with new credentials");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-    get_remote_url_from_file(config_file).await
+async fn create_cache_dir(cache_dir: &Path) -> Result<(), LFSError> {
+    fat_io_wrap_tokio(cache_dir, fs::create_dir_all)
+        .await
+        .map_err(|_| {
+            LFSError::DirectoryTraversalError("Could not create lfs cache directory".to_string())
+        })?;
+    Ok(())
 }
 
-fn remote_url_ssh_to_https(repo_url: String) -> Result<String, LFSError> {
-    let input_url = Url::parse(&repo_url)?;
-    if input_url.scheme() == "https" {
-        return Ok(repo_url);
-    } else if input_url.scheme() != "ssh" {
-        return Err(LFSError::InvalidFormat("Url is neither https nor ssh"));
-    }
-    let host = input_url
-        .host_str()
-        .ok_or(LFSError::InvalidFormat("Url had no valid host"))?;
-    let path = input_url.path();
-    Ok(format!("https://{host}{path}"))
+/// The directory holding the `lfs` folder of `repository`, `lfs.storage` if configured
+async fn get_storage_dir(repository: &Repository) -> PathBuf {
+    match repository.config().await {
+        Ok(config) => {
+            if let Some(storage_url) = config.get("lfs.storage") {
+                debug!("Found git lfs storage path: '{storage_url}'");
+                return repository.common_dir.join(storage_url);
+            }
+        }
+        Err(e) => warn!("Could not read git config: {e}"),
+    }
+    repository.common_dir.clone()
 }
 
-async fn get_cache_dir<P: AsRef<Path>>(
-    repo_root: P,
-    metadata: &primitives::MetaData,
-) -> Result<PathBuf, LFSError> {
+fn get_cache_dir(storage_dir: &Path, metadata: &primitives::MetaData) -> PathBuf {
     let oid_1 = &metadata.oid[0..2];
     let oid_2 = &metadata.oid[2..4];
 
-    let mut git_folder = get_real_repo_root(repo_root).await?.join(".git");
-    let config = git_folder.join("config");
-    if config.exists() {
-        debug!("Read git config file in {}", config.to_string_lossy());
-        let config_content = read_to_string(&config).await.unwrap_or_else(|e| {
-            warn!("Could not read git config: {e}");
-            String::new()
-        });
-        let mut config_content = config_content.lines().peekable();
-
-        while config_content.peek().is_some() {
-            let line = config_content.next().unwrap_or_default();
-            let line = line.trim();
-            if line.contains("[lfs]") {
-                while config_content.peek().is_some() {
-                    let next_line = config_content.next().unwrap_or_default();
-                    let next_line = next_line.trim();
-                    if let Some(storage_url) = next_line.strip_prefix("storage = ") {
-                        debug!("Found git lfs storage path: '{storage_url}'");
-                        git_folder = PathBuf::from(storage_url);
-                        break;
-                    }
+    storage_dir
+        .join("lfs")
+        .join("objects")
+        .join(oid_1)
+        .join(oid_2)
+}
+
+/// Requests download actions for `objects`, retrying failed requests up to `options.max_retry` times
+/// and as long as `auth` wants to after the server denied access
+async fn request_batch(
+    objects: &[&primitives::MetaData],
+    lfs_endpoint: &LfsEndpoint,
+    client: &reqwest::Client,
+    auth: &dyn AuthProvider,
+    options: &PullOptions,
+) -> Result<primitives::Batch, LFSError> {
+    let url = url::Url::parse(&lfs_endpoint.url)?;
+    let mut attempt = 1;
+    loop {
+        let headers = auth.headers(&url).await?;
+        match primitives::request_batch(objects, lfs_endpoint, client, &headers).await {
+            Ok(batch) => {
+                auth.accepted(&url, &headers).await;
+                return Ok(batch);
+            }
+            Err(LFSError::AccessDenied) => {
+                if !auth.rejected(&url, &headers).await? {
+                    return Err(LFSError::AccessDenied);
                 }
-                break;
+                debug!("Retrying with new credentials");
+            }
+            Err(e) if attempt < options.max_retry => {
+                error!("Batch request failed: {e}");
+                attempt += 1;
+                tokio::time::sleep(Duration::from_secs(1)).await;
             }
+            Err(e) => return Err(e),
         }
     }
+}
 
-    Ok(git_folder
-        .join("lfs")
-        .join("objects")
-        .join(oid_1)
-        .join(oid_2))
+/// State shared by all repositories pulled by one call of [`pull_file`], [`glob_recurse_pull_directory`]
+/// or [`recurse_pull_submodules`]
+struct PullSession {
+    clients: HttpClients,
+    /// The cache file of every object downloaded so far by its oid, so each object is downloaded once
+    downloaded: std::sync::Mutex<HashMap<String, PathBuf>>,
 }
 
-async fn get_file_cached<P: AsRef<Path>>(
-    repo_root: P,
-    metadata: &primitives::MetaData,
-    access_token: Option<&str>,
-    max_retry: u32,
-    randomizer_bytes: Option<usize>,
-    timeout: Option<u64>,
-) -> Result<(PathBuf, FilePullMode), LFSError> {
-    debug!("version: {}", &metadata.version);
-    let cache_dir = get_cache_dir(&repo_root, metadata).await?;
-    debug!("cache dir {:?}", &cache_dir);
-    let cache_file = cache_dir.join(&metadata.oid);
-    debug!("cache file {:?}", &cache_file);
-    let repo_url = remote_url_ssh_to_https(get_remote_url(&repo_root).await?)?;
-
-    if cache_file.is_file() {
-        Ok((cache_file, FilePullMode::UsedLocalCache))
+impl PullSession {
+    fn new(options: &PullOptions) -> Self {
+        PullSession {
+            clients: HttpClients::new(&options.connection),
+            downloaded: Default::default(),
+        }
+    }
+
+    /// The cache file `oid` was downloaded to earlier in this session
+    fn downloaded(&self, oid: &str) -> Option<PathBuf> {
+        self.downloaded.lock().unwrap().get(oid).cloned()
+    }
+}
+
+/// A pointer file whose object is not in the LFS cache yet
+struct MissingObject<'a> {
+    lfs_file: &'a Path,
+    metadata: primitives::MetaData,
+    cache_dir: PathBuf,
+}
+
+/// Downloads the `missing` objects into the LFS cache, requesting `options.batch_size` of them per batch call
+/// and downloading up to `concurrency` of them at the same time.
+async fn download_batches(
+    missing: &[MissingObject<'_>],
+    lfs_endpoint: &LfsEndpoint,
+    clients: &HttpClients,
+    auth: &dyn AuthProvider,
+    options: &PullOptions,
+    concurrency: usize,
+) -> Result<(), LFSError> {
+    let client = clients.get(lfs_endpoint).await?;
+    for chunk in missing.chunks(options.batch_size.max(1)) {
+        let objects: Vec<_> = chunk.iter().map(|object| &object.metadata).collect();
+        let batch = request_batch(&objects, lfs_endpoint, &client, auth, options).await?;
+        futures_util::stream::iter(chunk.iter().map(Ok))
+            .try_for_each_concurrent(concurrency, |object| {
+                download_missing(
+                    object,
+                    &batch,
+                    &client,
+                    lfs_endpoint,
+                    clients,
+                    auth,
+                    options,
+                )
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Downloads the `missing` objects into the LFS cache over a single `git-lfs-transfer` connection,
+/// requesting `options.batch_size` of them per `batch` message
+async fn download_ssh(
+    missing: &[MissingObject<'_>],
+    transfer: &ssh_transfer::SshTransfer,
+    options: &PullOptions,
+) -> Result<(), LFSError> {
+    let session = std::sync::Mutex::new(None);
+    let result = async {
+        for chunk in missing.chunks(options.batch_size.max(1)) {
+            let objects: Vec<_> = chunk
+                .iter()
+                .map(|object| (&object.metadata, object.cache_dir.as_path()))
+                .collect();
+            let temp_files = transfer
+                .download_files(
+                    &session,
+                    &objects,
+                    options.max_retry,
+                    options.randomizer_bytes,
+                    options.timeout,
+                )
+                .await?;
+            for (temp_file, object) in temp_files.into_iter().zip(chunk) {
+                store_in_cache(temp_file, &object.cache_dir.join(&object.metadata.oid)).await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Some(session) = session.into_inner().unwrap() {
+        if let Err(e) = session.close().await {
+            debug!("Could not close git-lfs-transfer session cleanly: {e}");
+        }
+    }
+    result
+}
+
+/// Downloads `object` into the LFS cache following its action in `batch`.
+/// If that fails, it is retried on its own with a fresh batch request.
+async fn download_missing(
+    object: &MissingObject<'_>,
+    batch: &primitives::Batch,
+    client: &reqwest::Client,
+    lfs_endpoint: &LfsEndpoint,
+    clients: &HttpClients,
+    auth: &dyn AuthProvider,
+    options: &PullOptions,
+) -> Result<(), LFSError> {
+    let temp_dir = Some(&object.cache_dir);
+    let download = batch.download(
+        &object.metadata,
+        client,
+        options.randomizer_bytes,
+        &temp_dir,
+    );
+    let result = match primitives::get_effective_timeout(options.timeout, object.metadata.size) {
+        Some(seconds) => tokio::time::timeout(Duration::from_secs(seconds), download)
+            .await
+            .unwrap_or(Err(LFSError::Timeout)),
+        None => download.await,
+    };
+    let temp_file = match result {
+        Ok(temp_file) => temp_file,
+        Err(e) if primitives::is_object_error(&e) => return Err(e),
+        Err(e) => {
+            warn!(
+                "Download of {} failed: {e}, retrying it on its own",
+                object.lfs_file.to_string_lossy()
+            );
+            download_http(
+                &object.metadata,
+                lfs_endpoint,
+                clients,
+                auth,
+                options,
+                &object.cache_dir,
+            )
+            .await?
+        }
+    };
+    store_in_cache(temp_file, &object.cache_dir.join(&object.metadata.oid)).await
+}
+
+/// Moves a downloaded object to `cache_file`, unless another process was faster
+async fn store_in_cache(
+    temp_file: tempfile::NamedTempFile,
+    cache_file: &Path,
+) -> Result<(), LFSError> {
+    if cache_file.exists() {
+        info!(
+            "cache file {:?} is already written from other process",
+            &cache_file
+        );
+        return Ok(());
+    }
+    let renamed = fs::rename(&temp_file.path(), cache_file).await;
+    if renamed.is_err() && cache_file.is_file() {
+        info!(
+            "cache file {:?} was written concurrently from another process",
+            &cache_file
+        );
+        return Ok(());
+    }
+    renamed.map_err(|e| {
+        error!(
+            "Could not rename {:?} to {:?}: {:?}",
+            temp_file.path(),
+            cache_file,
+            &e
+        );
+        LFSError::FatFileIOError(FatIOError::from_std_io_err(
+            e,
+            temp_file.path().to_path_buf(),
+        ))
+    })
+}
+
+/// Adds the object `source` downloaded for another repository to `cache_file`, hard linked if possible
+async fn copy_into_cache(source: &Path, cache_file: &Path) -> Result<(), LFSError> {
+    if fs::hard_link(source, cache_file).await.is_err() {
+        fs::copy(source, cache_file)
+            .await
+            .map_err(|e| FatIOError::from_std_io_err(e, source.to_path_buf()))?;
+    }
+    Ok(())
+}
+
+/// Replaces the pointer `lfs_file` with `cache_file`, hard linked if both are on the same device
+async fn link_from_cache(cache_file: &Path, lfs_file: &Path) -> Result<(), LFSError> {
+    let is_of_same_root = are_paths_on_same_devices(cache_file, lfs_file).await?;
+    fat_io_wrap_tokio(&lfs_file, fs::remove_file).await?;
+
+    if is_of_same_root {
+        info!("Setting hard link");
+        fs::hard_link(cache_file, lfs_file)
+            .await
+            .map_err(|e| FatIOError::from_std_io_err(e, cache_file.to_path_buf()))?;
     } else {
-        fat_io_wrap_tokio(&cache_dir, fs::create_dir_all)
+        info!("Copying file");
+        fs::copy(cache_file, lfs_file)
             .await
-            .map_err(|_| {
-                LFSError::DirectoryTraversalError(
-                    "Could not create lfs cache directory".to_string(),
-                )
-            })?;
+            .map_err(|e| FatIOError::from_std_io_err(e, cache_file.to_path_buf()))?;
+    }
+    Ok(())
+}
 
-        let temp_file = primitives::download_file(
-            metadata,
-            &repo_url,
-            access_token,
-            max_retry,
-            randomizer_bytes,
-            timeout,
-            Some(cache_dir),
-        )
-        .await?;
-        if cache_file.exists() {
+/// Pulls `files` of `repository`. The objects missing in the LFS cache are downloaded first,
+/// in batches of `options.batch_size`. Objects shared by several pointers or already
+/// downloaded earlier in the `session` are downloaded only once. Returns how each file was pulled, in order.
+async fn pull_files_in(
+    repository: &Repository,
+    files: &[PathBuf],
+    session: &PullSession,
+    auth: Option<&dyn AuthProvider>,
+    options: &PullOptions,
+) -> Result<Vec<FilePullMode>, LFSError> {
+    let mut modes = Vec::with_capacity(files.len());
+    let mut pointers = Vec::new();
+    let mut missing = Vec::new();
+    // the oids in `missing`, so pointers sharing an object are found without scanning it
+    let mut missing_oids = HashSet::new();
+    let storage_dir = get_storage_dir(repository).await;
+    for lfs_file in files {
+        info!("Pulling file {}", lfs_file.to_string_lossy());
+        if !primitives::is_lfs_node_file(lfs_file).await? {
             info!(
-                "cache file {:?} is already written from other process",
-                &cache_file
+                "File ({}) not an lfs-node file - pulled already.",
+                lfs_file.file_name().unwrap().to_string_lossy()
+            );
+            modes.push(FilePullMode::WasAlreadyPresent);
+            continue;
+        }
+
+        debug!("parsing metadata");
+        let metadata = primitives::parse_lfs_file(lfs_file).await?;
+        debug!("version: {}", &metadata.version);
+        if metadata.hash != Some(primitives::Hash::SHA256) {
+            warn!(
+                "File ({}) uses an unsupported hash algorithm, leaving its pointer",
+                lfs_file.to_string_lossy()
             );
+            modes.push(FilePullMode::UnsupportedHash);
+            continue;
+        }
+        let cache_dir = get_cache_dir(&storage_dir, &metadata);
+        let cache_file = cache_dir.join(&metadata.oid);
+        debug!("cache file {:?}", &cache_file);
+        pointers.push((lfs_file, cache_file.clone(), modes.len()));
+        if let Some(source) = session.downloaded(&metadata.oid) {
+            if !cache_file.is_file() {
+                create_cache_dir(&cache_dir).await?;
+                copy_into_cache(&source, &cache_file).await?;
+            }
+            modes.push(FilePullMode::Deduplicated);
+        } else if cache_file.is_file() {
+            modes.push(FilePullMode::UsedLocalCache);
+        } else if !missing_oids.insert(metadata.oid.clone()) {
+            modes.push(FilePullMode::Deduplicated);
         } else {
-            fs::rename(&temp_file.path(), cache_file.as_path())
-                .map_err(|e| {
-                    error!(
-                        "Could not rename {:?} to {:?}: {:?}",
-                        temp_file.path(),
-                        cache_file.as_path(),
-                        &e
-                    );
-                    LFSError::FatFileIOError(FatIOError::from_std_io_err(
-                        e,
-                        temp_file.path().to_path_buf(),
-                    ))
-                })
+            modes.push(FilePullMode::DownloadedFromRemote);
+            missing.push(MissingObject {
+                lfs_file,
+                metadata,
+                cache_dir,
+            });
+        }
+    }
+
+    if !missing.is_empty() {
+        debug!("Downloading {} objects", missing.len());
+        for object in &missing {
+            create_cache_dir(&object.cache_dir).await?;
+        }
+        match get_transfer(repository, options).await? {
+            Transfer::Http {
+                endpoint: lfs_endpoint,
+                helpers,
+                netrc,
+                ssh_auth,
+                concurrency,
+            } => {
+                let git_auth;
+                let auth: &dyn AuthProvider = match (auth, &ssh_auth) {
+                    (Some(auth), _) => auth,
+                    (None, Some(ssh_auth)) => ssh_auth.as_ref(),
+                    (None, None)
+                        if lfs_endpoint
+                            .headers
+                            .keys()
+                            .any(|name| name.eq_ignore_ascii_case("authorization")) =>
+                    {
+                        &NoAuth
+                    }
+                    (None, None) => {
+                        git_auth = credentials::GitCredentialAuth::new(&helpers, netrc.as_ref());
+                        &git_auth
+                    }
+                };
+                download_batches(
+                    &missing,
+                    &lfs_endpoint,
+                    &session.clients,
+                    auth,
+                    options,
+                    concurrency,
+                )
                 .await?;
+            }
+            Transfer::Ssh(ssh_transfer) => {
+                download_ssh(&missing, &ssh_transfer, options).await?;
+            }
         }
+        session
+            .downloaded
+            .lock()
+            .unwrap()
+            .extend(missing.iter().map(|object| {
+                let oid = object.metadata.oid.clone();
+                let cache_file = object.cache_dir.join(&oid);
+                (oid, cache_file)
+            }));
+    }
+    let deduplicated = modes
+        .iter()
+        .filter(|&&mode| mode == FilePullMode::Deduplicated)
+        .count();
+    if deduplicated > 0 {
+        info!("{deduplicated} files share their object with another file and were not downloaded again");
+    }
 
-        Ok((cache_file, FilePullMode::DownloadedFromRemote))
+    for (lfs_file, cache_file, index) in pointers {
+        info!(
+            "Found file (Origin: {:?}), linking to {}",
+            modes[index],
+            lfs_file.to_string_lossy()
+        );
+        link_from_cache(&cache_file, lfs_file).await?;
     }
+    Ok(modes)
 }
 
 /// Ensures a single file is pulled from the lfs
-/// Currently only token/https auth is supported.
+/// Authentication is pluggable via [`AuthProvider`].
 /// Various errors can occur which are covered by the `LFSError` struct
 /// The return value specifies the origin of the file
 /// # Arguments
 ///
 /// * `lfs_file` - Anything describing a path to an lfs node file
 ///
-/// * `access_token` - The token for Bearer-Auth via HTTPS
+/// * `auth` - How to authenticate against the LFS server, `None` asks the git credential helpers
+///
+/// * `options` - Retry, timeout and remote settings, see [`PullOptions`]
 ///
 pub async fn pull_file<P: AsRef<Path>>(
     lfs_file: P,
-    access_token: Option<&str>,
-    max_retry: u32,
-    randomizer_bytes: Option<usize>,
-    timeout: Option<u64>,
+    auth: Option<&dyn AuthProvider>,
+    options: &PullOptions,
 ) -> Result<FilePullMode, LFSError> {
     let lfs_file = lfs_file.as_ref();
-
-    info!("Pulling file {}", lfs_file.to_string_lossy());
-    if !primitives::is_lfs_node_file(&lfs_file).await? {
+    if !primitives::is_lfs_node_file(lfs_file).await? {
         info!(
             "File ({}) not an lfs-node file - pulled already.",
-            lfs_file.file_name().unwrap().to_string_lossy()
+            lfs_file.to_string_lossy()
         );
         return Ok(FilePullMode::WasAlreadyPresent);
     }
-
-    debug!("parsing metadata");
-    let metadata = primitives::parse_lfs_file(&lfs_file).await?;
-    debug!("Downloading file");
-    let repo_root = get_repo_root(&lfs_file).await.map_err(|e| {
-        LFSError::DirectoryTraversalError(format!("Could not find git repo root: {e:?}"))
-    })?;
-    let (file_name_cached, origin) = get_file_cached(
-        &repo_root,
-        &metadata,
-        access_token,
-        max_retry,
-        randomizer_bytes,
-        timeout,
+    let repository = discover_repository(lfs_file).await?;
+    let session = PullSession::new(options);
+    let modes = pull_files_in(
+        &repository,
+        &[lfs_file.to_path_buf()],
+        &session,
+        auth,
+        options,
     )
     .await?;
-    info!(
-        "Found file (Origin: {:?}), linking to {}",
-        origin,
-        lfs_file.to_string_lossy()
-    );
-
-    let is_of_same_root = are_paths_on_same_devices(&file_name_cached, lfs_file).await?;
-    fat_io_wrap_tokio(&lfs_file, fs::remove_file).await?;
-
-    if is_of_same_root {
-        info!("Setting hard link");
-        fs::hard_link(&file_name_cached, lfs_file)
-            .await
-            .map_err(|e| FatIOError::from_std_io_err(e, file_name_cached.clone()))?;
-    } else {
-        info!("Copying file");
-        fs::copy(&file_name_cached, lfs_file)
-            .await
-            .map_err(|e| FatIOError::from_std_io_err(e, file_name_cached.clone()))?;
-    }
+    Ok(modes[0])
+}
 
-    Ok(origin)
+async fn discover_repository(lfs_file: &Path) -> Result<Repository, LFSError> {
+    Repository::discover(lfs_file).await.map_err(|e| {
+        LFSError::DirectoryTraversalError(format!("Could not find git repo root: {e:?}"))
+    })
 }
 
 fn glob_recurse(wildcard_pattern: &str) -> Result<Vec<PathBuf>, LFSError> {
@@ -320,46 +754,172 @@ async fn are_paths_on_same_devices(
 }
 
 /// Pulls a glob recurse expression
+/// All pointer files are collected first, their objects are requested in batches of [`PullOptions::batch_size`]
 /// In addition to the same errors as in `pull_file`, more `LFSError::DirectoryTraversalError` can occur if something is wrong with the pattern
 /// # Arguments
 ///
 /// * `wildcard_pattern` - the pattern to glob-recurse
 ///
-/// * `access_token` - the token for Bearer-Auth via HTTPS
-///
-/// * `max retry` - max number of retry attempt when http request fails
+/// * `auth` - how to authenticate against the LFS server, `None` asks the git credential helpers
 ///
-/// * `randomizer bytes` - bytes used to create a randomized named temp file
+/// * `options` - retry, timeout and remote settings, see [`PullOptions`]
 ///
 /// # Examples
 ///
 /// Load all .jpg files from all subdirectories
 /// ```no_run
-/// let result = lfspull::glob_recurse_pull_directory("dir/to/pull/**/*.jpg", Some("secret-token"), 3, Some(5), Some(0));
+/// let options = lfspull::PullOptions {
+///     randomizer_bytes: Some(5),
+///     timeout: Some(0),
+///     ..Default::default()
+/// };
+/// let auth = lfspull::BasicAuth::new("oauth2", "secret-token");
+/// let result = lfspull::glob_recurse_pull_directory("dir/to/pull/**/*.jpg", Some(&auth), &options);
 /// ```
 ///
 pub async fn glob_recurse_pull_directory(
     wildcard_pattern: &str,
-    access_token: Option<&str>,
-    max_retry: u32,
-    randomizer_bytes: Option<usize>,
-    timeout: Option<u64>,
+    auth: Option<&dyn AuthProvider>,
+    options: &PullOptions,
 ) -> Result<Vec<(String, FilePullMode)>, LFSError> {
-    let mut result_vec = Vec::new();
     let files = glob_recurse(wildcard_pattern)?;
-    for path in files {
-        result_vec.push((
-            path.to_string_lossy().to_string(),
-            pull_file(&path, access_token, max_retry, randomizer_bytes, timeout).await?,
-        ));
+    let session = PullSession::new(options);
+    let mut modes = vec![FilePullMode::WasAlreadyPresent; files.len()];
+    // pointers are pulled together per repository, so their objects share batch requests
+    let mut repositories: Vec<(Repository, Vec<usize>)> = Vec::new();
+    for (index, path) in files.iter().enumerate() {
+        if !primitives::is_lfs_node_file(path).await? {
+            info!(
+                "File ({}) not an lfs-node file - pulled already.",
+                path.to_string_lossy()
+            );
+            continue;
+        }
+        let repository = discover_repository(path).await?;
+        match repositories
+            .iter_mut()
+            .find(|(known, _)| known.git_dir == repository.git_dir)
+        {
+            Some((_, indices)) => indices.push(index),
+            None => repositories.push((repository, vec![index])),
+        }
+    }
+    for (repository, indices) in repositories {
+        let pointers: Vec<_> = indices.iter().map(|&index| files[index].clone()).collect();
+        let pulled = pull_files_in(&repository, &pointers, &session, auth, options).await?;
+        for (index, mode) in indices.into_iter().zip(pulled) {
+            modes[index] = mode;
+        }
+    }
+
+    Ok(files
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .zip(modes)
+        .collect())
+}
+
+/// The files pulled from one repository by [`recurse_pull_submodules`]
+#[derive(Debug, Clone)]
+pub struct SubmodulePullResult {
+    /// The submodule name from `.gitmodules`, `None` for the repository pulling was started in
+    pub name: Option<String>,
+    /// The work tree of the (sub)module
+    pub path: PathBuf,
+    /// Every matching file with the origin it was pulled from
+    pub files: Vec<(String, FilePullMode)>,
+}
+
+/// Pulls the repository containing `repo_path` and all of its initialised submodules, recursively.
+/// Every submodule is pulled with the remote, credentials and LFS cache of its own git dir.
+/// # Arguments
+///
+/// * `repo_path` - a path inside the repository to start from
+///
+/// * `wildcard_pattern` - the pattern to glob-recurse, relative to each work tree, e.g. `**/*`
+///
+/// * `auth` - how to authenticate against the LFS server, `None` asks the git credential helpers
+///
+/// * `options` - retry, timeout and remote settings, see [`PullOptions`].
+///   `remote` only applies to the top-level repository, submodules use their own upstream remote
+///
+pub async fn recurse_pull_submodules<P: AsRef<Path>>(
+    repo_path: P,
+    wildcard_pattern: &str,
+    auth: Option<&dyn AuthProvider>,
+    options: &PullOptions,
+) -> Result<Vec<SubmodulePullResult>, LFSError> {
+    let submodule_options = PullOptions {
+        remote: None,
+        ..options.clone()
+    };
+    let session = PullSession::new(options);
+    let mut pending = vec![(None, Repository::discover(repo_path).await?)];
+    let mut results = Vec::new();
+    while let Some((name, repository)) = pending.pop() {
+        let Some(work_tree) = repository.work_tree.clone() else {
+            return Err(LFSError::DirectoryTraversalError(format!(
+                "Repository {} has no work tree",
+                repository.git_dir.to_string_lossy()
+            )));
+        };
+        info!("Pulling repository {}", work_tree.to_string_lossy());
+        let submodules = repository.submodules().await?;
+        let options = if name.is_none() {
+            options
+        } else {
+            &submodule_options
+        };
+
+        let pattern = Path::new(&glob::Pattern::escape(&work_tree.to_string_lossy()))
+            .join(wildcard_pattern)
+            .to_string_lossy()
+            .to_string();
+        let mut paths = Vec::new();
+        for path in glob_recurse(&pattern)? {
+            let relative = path.strip_prefix(&work_tree).unwrap_or(&path);
+            let belongs_to_submodule = submodules.iter().any(|submodule| {
+                submodule
+                    .repository
+                    .work_tree
+                    .as_ref()
+                    .is_some_and(|submodule_tree| path.starts_with(submodule_tree))
+            });
+            if !path.is_file()
+                || relative.components().any(|c| c.as_os_str() == ".git")
+                || belongs_to_submodule
+            {
+                continue;
+            }
+            paths.push(path);
+        }
+        let modes = pull_files_in(&repository, &paths, &session, auth, options).await?;
+        let files = paths
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .zip(modes)
+            .collect();
+        results.push(SubmodulePullResult {
+            name,
+            path: work_tree,
+            files,
+        });
+        pending.extend(
+            submodules
+                .into_iter()
+                .rev()
+                .map(|submodule| (Some(submodule.name), submodule.repository)),
+        );
     }
 
-    Ok(result_vec)
+    Ok(results)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use tracing::error;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -381,53 +941,430 @@ mod tests {
         );
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_find_current_repo_root_from_cargo_directory() {
-        let result = get_repo_root(env!("CARGO_MANIFEST_DIR"))
-            .await
-            .map_err(|e| error!("{:#?}", e));
-        assert!(result.is_ok());
+    async fn http_endpoint(
+        repo_path: &Path,
+        remote: Option<&str>,
+    ) -> Result<LfsEndpoint, LFSError> {
+        let repository = Repository::discover(repo_path).await?;
+        let options = PullOptions {
+            remote: remote.map(str::to_owned),
+            ..Default::default()
+        };
+        match get_transfer(&repository, &options).await? {
+            Transfer::Http { endpoint, .. } => Ok(*endpoint),
+            Transfer::Ssh(_) => panic!("expected an http endpoint"),
+        }
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_find_current_repo_root_from_source_file() {
+    async fn current_repo_lfs_endpoint_correct() {
         let current_file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(file!());
-        let result = get_repo_root(current_file_path)
+
+        let lfs_endpoint = http_endpoint(&current_file_path, None)
             .await
-            .map_err(|e| error!("{:#?}", e));
-        assert!(result.is_ok());
+            .map_err(|e| error!("{:#?}", e))
+            .expect("Could not get lfs endpoint");
+
+        assert!(url::Url::parse(&lfs_endpoint.url).is_ok());
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn current_repo_remote_url_correct() {
-        let current_file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(file!());
+    async fn lfs_endpoint_honors_branch_tracking_and_lfsconfig() {
+        let repo = tempfile::tempdir().expect("could not create temp dir");
+        let git_dir = repo.path().join(".git");
+        fs::create_dir_all(&git_dir).await.unwrap();
+        fs::write(
+            git_dir.join("config"),
+            "[remote \"origin\"]\n\turl = https://origin.example.com/repo.git\n\
+             [remote \"mirror\"]\n\turl = https://mirror.example.com/repo.git\n\
+             [branch \"feature\"]\n\tremote = mirror\n",
+        )
+        .await
+        .unwrap();
 
-        let repo_root = get_repo_root(current_file_path)
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/feature\n")
             .await
-            .map_err(|e| error!("{:#?}", e))
-            .expect("Could not get repo root");
+            .unwrap();
+        let endpoint = http_endpoint(repo.path(), None).await.unwrap();
+        assert_eq!(endpoint.url, "https://mirror.example.com/repo.git/info/lfs");
 
-        let repo_remote = get_remote_url(repo_root)
+        let endpoint = http_endpoint(repo.path(), Some("origin")).await.unwrap();
+        assert_eq!(endpoint.url, "https://origin.example.com/repo.git/info/lfs");
+
+        let result = http_endpoint(repo.path(), Some("upstream")).await;
+        assert!(matches!(result, Err(LFSError::UnknownRemote(_))));
+
+        fs::write(
+            repo.path().join(".lfsconfig"),
+            "[lfs]\n\turl = https://lfs.example.com/repo\n",
+        )
+        .await
+        .unwrap();
+        let endpoint = http_endpoint(repo.path(), None).await.unwrap();
+        assert_eq!(endpoint.url, "https://lfs.example.com/repo");
+    }
+
+    /// A fake LFS server over plain http, answering batch requests with download actions for `objects`
+    struct FakeLfsServer {
+        url: String,
+        /// The number of objects in each batch request
+        batches: Arc<std::sync::Mutex<Vec<usize>>>,
+        /// The most downloads that were running at the same time
+        max_downloads: Arc<AtomicUsize>,
+        /// The oid of every downloaded object
+        downloads: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    async fn serve_lfs(objects: HashMap<String, Vec<u8>>) -> FakeLfsServer {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/lfs", listener.local_addr().unwrap());
+        let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let max_downloads = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+        let downloads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let server = FakeLfsServer {
+            url: url.clone(),
+            batches: batches.clone(),
+            max_downloads: max_downloads.clone(),
+            downloads: downloads.clone(),
+        };
+        let objects = Arc::new(objects);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (url, batches, objects) = (url.clone(), batches.clone(), objects.clone());
+                let (max_downloads, running) = (max_downloads.clone(), running.clone());
+                let downloads = downloads.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut request_line = String::new();
+                        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut content_length = 0;
+                        loop {
+                            let mut line = String::new();
+                            stream.read_line(&mut line).await.unwrap();
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    content_length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        stream.read_exact(&mut body).await.unwrap();
+
+                        let path = request_line.split(' ').nth(1).unwrap().to_owned();
+                        let (content_type, response) = if path == "/lfs/objects/batch" {
+                            let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                            let requested = request["objects"].as_array().unwrap();
+                            batches.lock().unwrap().push(requested.len());
+                            let answered: Vec<_> = requested
+                                .iter()
+                                .map(|object| {
+                                    let oid = object["oid"].as_str().unwrap();
+                                    serde_json::json!({
+                                        "oid": oid,
+                                        "size": object["size"],
+                                        "actions": { "download": { "href": format!("{url}/blob/{oid}") } }
+                                    })
+                                })
+                                .collect();
+                            let response = serde_json::json!({ "objects": answered });
+                            (
+                                "application/vnd.git-lfs+json",
+                                response.to_string().into_bytes(),
+                            )
+                        } else {
+                            let oid = path.rsplit('/').next().unwrap();
+                            downloads.lock().unwrap().push(oid.to_owned());
+                            // keep downloads running for a while, so concurrent ones overlap
+                            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_downloads.fetch_max(now_running, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            ("application/octet-stream", objects[oid].clone())
+                        };
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+                            response.len()
+                        );
+                        let stream = stream.get_mut();
+                        stream.write_all(head.as_bytes()).await.unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+        server
+    }
+
+    fn sha256_hex(content: &str) -> String {
+        use sha2::{Digest, Sha256};
+        hex::encode(Sha256::digest(content.as_bytes()))
+    }
+
+    /// The objects for `contents` as served by [`serve_lfs`]
+    fn lfs_objects(contents: &[&str]) -> HashMap<String, Vec<u8>> {
+        contents
+            .iter()
+            .map(|content| (sha256_hex(content), content.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// Creates a repository using `lfs_url` with the pointer files `file<index>.bin` for `contents`
+    async fn repository_with_pointers(dir: &Path, lfs_url: &str, contents: &[&str]) -> PathBuf {
+        let root = std::fs::canonicalize(dir).unwrap();
+        let git_dir = root.join(".git");
+        fs::create_dir_all(git_dir.join("objects")).await.unwrap();
+        fs::create_dir_all(git_dir.join("refs")).await.unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n")
             .await
-            .map_err(|e| error!("{:#?}", e))
-            .expect("Could not get repo remote");
+            .unwrap();
+        fs::write(
+            git_dir.join("config"),
+            format!("[lfs]\n\turl = {lfs_url}\n"),
+        )
+        .await
+        .unwrap();
+        for (index, content) in contents.iter().enumerate() {
+            let pointer = format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize {}\n",
+                sha256_hex(content),
+                content.len()
+            );
+            let path = root.join(format!("file{index}.bin"));
+            // pulled files are hard links into the cache, which must not be overwritten
+            let _ = fs::remove_file(&path).await;
+            fs::write(path, pointer).await.unwrap();
+        }
+        root
+    }
 
-        assert!(Url::parse(&repo_remote).is_ok());
+    fn local_options() -> PullOptions {
+        PullOptions {
+            allow_http: true,
+            git_ref: BatchRef::Omit,
+            ..Default::default()
+        }
     }
 
-    const REPO_REMOTE: &str = "ssh://git@github.com/VolumeGraphics/lfspull.git";
-    const REPO_REMOTE_HTTPS: &str = "https://github.com/VolumeGraphics/lfspull.git";
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn current_repo_remote_https_transform_works() {
-        let repo_url_https =
-            remote_url_ssh_to_https(REPO_REMOTE.to_string()).expect("Could not parse url");
-        assert_eq!(repo_url_https, REPO_REMOTE_HTTPS);
+    async fn glob_pull_requests_objects_in_batches() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let contents = ["first", "second", "third", "fourth", "fifth"];
+        let server = serve_lfs(lfs_objects(&contents)).await;
+        let root = repository_with_pointers(dir.path(), &server.url, &contents).await;
+        fs::write(root.join("plain.bin"), "not a pointer")
+            .await
+            .unwrap();
+        let unsupported = "version https://git-lfs.github.com/spec/v1\noid sha1:0a4d55a8d778e5022fab701977c5d840bbc486d0\nsize 5\n";
+        fs::write(root.join("other.bin"), unsupported)
+            .await
+            .unwrap();
+
+        // put the first object into the cache, then restore its pointer
+        pull_file(root.join("file0.bin"), Some(&NoAuth), &local_options())
+            .await
+            .expect("could not pull single file");
+        assert_eq!(*server.batches.lock().unwrap(), vec![1]);
+        repository_with_pointers(dir.path(), &server.url, &contents).await;
+
+        let options = PullOptions {
+            batch_size: 2,
+            ..local_options()
+        };
+        let pattern = format!("{}/*.bin", glob::Pattern::escape(&root.to_string_lossy()));
+        let results = glob_recurse_pull_directory(&pattern, Some(&NoAuth), &options)
+            .await
+            .expect("could not pull directory");
+        assert_eq!(*server.batches.lock().unwrap(), vec![1, 2, 2]);
+        let modes: Vec<_> = results.iter().map(|(_, mode)| *mode).collect();
+        assert_eq!(
+            modes,
+            vec![
+                FilePullMode::UsedLocalCache,
+                FilePullMode::DownloadedFromRemote,
+                FilePullMode::DownloadedFromRemote,
+                FilePullMode::DownloadedFromRemote,
+                FilePullMode::DownloadedFromRemote,
+                FilePullMode::UnsupportedHash,
+                FilePullMode::WasAlreadyPresent,
+            ]
+        );
+        let left = fs::read_to_string(root.join("other.bin")).await.unwrap();
+        assert_eq!(left, unsupported);
+        for (index, content) in contents.iter().enumerate() {
+            let pulled = fs::read_to_string(root.join(format!("file{index}.bin")))
+                .await
+                .unwrap();
+            assert_eq!(&pulled, content);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn downloads_run_concurrently_up_to_the_limit() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        // the last two pointers share their object with the first two
+        let contents = ["a", "b", "c", "d", "e", "f", "a", "b"];
+        let server = serve_lfs(lfs_objects(&contents)).await;
+        let root = repository_with_pointers(dir.path(), &server.url, &contents).await;
+        let config = root.join(".git").join("config");
+        let lfs_config = format!("[lfs]\n\turl = {}\n\tconcurrenttransfers = 2\n", server.url);
+        fs::write(&config, lfs_config).await.unwrap();
+        let pattern = format!("{}/*.bin", glob::Pattern::escape(&root.to_string_lossy()));
+
+        let results = glob_recurse_pull_directory(&pattern, Some(&NoAuth), &local_options())
+            .await
+            .expect("could not pull directory");
+        assert_eq!(server.max_downloads.load(Ordering::SeqCst), 2);
+        let pulled: Vec<_> = results.iter().map(|(name, _)| name.clone()).collect();
+        let expected: Vec<_> = (0..contents.len())
+            .map(|index| {
+                root.join(format!("file{index}.bin"))
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(pulled, expected);
+        for (index, content) in contents.iter().enumerate() {
+            let pulled = fs::read_to_string(root.join(format!("file{index}.bin")))
+                .await
+                .unwrap();
+            assert_eq!(&pulled, content);
+        }
+
+        fs::remove_dir_all(root.join(".git").join("lfs"))
+            .await
+            .unwrap();
+        repository_with_pointers(dir.path(), &server.url, &contents).await;
+        let options = PullOptions {
+            concurrency: Some(4),
+            ..local_options()
+        };
+        server.max_downloads.store(0, Ordering::SeqCst);
+        glob_recurse_pull_directory(&pattern, Some(&NoAuth), &options)
+            .await
+            .expect("could not pull directory");
+        assert_eq!(server.max_downloads.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn shared_objects_are_downloaded_once_per_pull() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let server = serve_lfs(lfs_objects(&["same", "other"])).await;
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        fs::create_dir_all(&first).await.unwrap();
+        fs::create_dir_all(&second).await.unwrap();
+        let first = repository_with_pointers(&first, &server.url, &["same", "other", "same"]).await;
+        let second = repository_with_pointers(&second, &server.url, &["same"]).await;
+
+        let pattern = format!(
+            "{}/*/*.bin",
+            glob::Pattern::escape(&std::fs::canonicalize(dir.path()).unwrap().to_string_lossy())
+        );
+        let results = glob_recurse_pull_directory(&pattern, Some(&NoAuth), &local_options())
+            .await
+            .expect("could not pull directory");
+        assert_eq!(*server.batches.lock().unwrap(), vec![2]);
+        let mut downloads = server.downloads.lock().unwrap().clone();
+        downloads.sort();
+        let mut expected = vec![sha256_hex("same"), sha256_hex("other")];
+        expected.sort();
+        assert_eq!(downloads, expected);
+        let modes: Vec<_> = results.iter().map(|(_, mode)| *mode).collect();
+        assert_eq!(
+            modes,
+            vec![
+                FilePullMode::DownloadedFromRemote,
+                FilePullMode::DownloadedFromRemote,
+                FilePullMode::Deduplicated,
+                FilePullMode::Deduplicated,
+            ]
+        );
+        for (file, content) in [
+            (first.join("file0.bin"), "same"),
+            (first.join("file1.bin"), "other"),
+            (first.join("file2.bin"), "same"),
+            (second.join("file0.bin"), "same"),
+        ] {
+            assert_eq!(fs::read_to_string(file).await.unwrap(), content);
+        }
+        let cached = second
+            .join(".git/lfs/objects")
+            .join(&sha256_hex("same")[0..2]);
+        assert!(cached
+            .join(&sha256_hex("same")[2..4])
+            .join(sha256_hex("same"))
+            .is_file());
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn current_repo_remote_https_transform_works_identity() {
-        let repo_url_https =
-            remote_url_ssh_to_https(REPO_REMOTE_HTTPS.to_string()).expect("Could not parse url");
-        assert_eq!(repo_url_https.as_str(), REPO_REMOTE_HTTPS);
+    async fn recurse_submodules_reports_files_per_repository() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        for git_dir in [root.join(".git"), root.join(".git/modules/sub")] {
+            fs::create_dir_all(git_dir.join("objects")).await.unwrap();
+            fs::create_dir_all(git_dir.join("refs")).await.unwrap();
+            fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n")
+                .await
+                .unwrap();
+            fs::write(git_dir.join("notes.txt"), "not part of the work tree")
+                .await
+                .unwrap();
+        }
+        fs::write(
+            root.join(".gitmodules"),
+            "[submodule \"sub\"]\n\tpath = sub\n\turl = ../sub.git\n",
+        )
+        .await
+        .unwrap();
+        fs::create_dir_all(root.join("sub").join("nested"))
+            .await
+            .unwrap();
+        fs::write(
+            root.join("sub").join(".git"),
+            "gitdir: ../.git/modules/sub\n",
+        )
+        .await
+        .unwrap();
+        fs::write(root.join("top.txt"), "plain file").await.unwrap();
+        fs::write(
+            root.join("sub").join("nested").join("inner.txt"),
+            "plain file",
+        )
+        .await
+        .unwrap();
+
+        let results = recurse_pull_submodules(&root, "**/*.txt", None, &PullOptions::default())
+            .await
+            .expect("could not pull submodules");
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, None);
+        assert_eq!(results[0].path, root);
+        assert_eq!(
+            results[0].files,
+            vec![(
+                root.join("top.txt").to_string_lossy().to_string(),
+                FilePullMode::WasAlreadyPresent
+            )]
+        );
+        assert_eq!(results[1].name.as_deref(), Some("sub"));
+        assert_eq!(results[1].path, root.join("sub"));
+        assert_eq!(
+            results[1].files,
+            vec![(
+                root.join("sub/nested/inner.txt")
+                    .to_string_lossy()
+                    .to_string(),
+                FilePullMode::WasAlreadyPresent
+            )]
+        );
     }
 }
@@ -0,0 +1,174 @@
+use crate::prelude::*;
+use std::path::PathBuf;
+use tokio::fs::read_to_string;
+use tracing::debug;
+use vg_errortools::fat_io_wrap_tokio;
+
+/// A login from a `.netrc` file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetrcLogin {
+    pub login: Option<String>,
+    pub password: Option<String>,
+}
+
+/// The logins of a `.netrc` file, as read by curl and git-lfs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Netrc {
+    machines: Vec<(String, NetrcLogin)>,
+    default: Option<NetrcLogin>,
+}
+
+/// Splits netrc contents into tokens, dropping `#` comments and the bodies of `macdef` definitions
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut lines = input.lines();
+    while let Some(line) = lines.next() {
+        let mut chars = line.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let Some(&first) = chars.peek() else {
+                break;
+            };
+            if first == '#' {
+                break;
+            }
+            let mut token = String::new();
+            if first == '"' {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => token.extend(chars.next()),
+                        c => token.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c);
+                }
+            }
+            tokens.push(token);
+        }
+        if tokens.len() >= 2 && tokens[tokens.len() - 2] == "macdef" {
+            // the macro body runs until the next empty line
+            tokens.truncate(tokens.len() - 2);
+            for line in lines.by_ref() {
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+    tokens
+}
+
+impl Netrc {
+    /// Parses the contents of a `.netrc` file
+    pub fn parse(input: &str) -> Self {
+        let mut netrc = Netrc::default();
+        let mut tokens = tokenize(input).into_iter();
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "machine" => {
+                    let Some(host) = tokens.next() else {
+                        break;
+                    };
+                    netrc.machines.push((host, NetrcLogin::default()));
+                }
+                "default" => {
+                    // the default entry has to come last
+                    netrc.default = Some(NetrcLogin::default());
+                }
+                "login" | "password" | "account" => {
+                    let value = tokens.next();
+                    let login = match &mut netrc.default {
+                        Some(default) => Some(default),
+                        None => netrc.machines.last_mut().map(|(_, login)| login),
+                    };
+                    match (login, token.as_str()) {
+                        (Some(login), "login") => login.login = value,
+                        (Some(login), "password") => login.password = value,
+                        _ => {}
+                    }
+                }
+                unknown => debug!("Ignoring unknown netrc token '{unknown}'"),
+            }
+        }
+        netrc
+    }
+
+    /// Reads `$NETRC`, or `.netrc` (`_netrc` on windows) in the home directory.
+    /// A missing file is treated as empty.
+    pub async fn load() -> Result<Self, LFSError> {
+        let path = match std::env::var_os("NETRC") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let Some(home) =
+                    std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))
+                else {
+                    return Ok(Netrc::default());
+                };
+                let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+                PathBuf::from(home).join(name)
+            }
+        };
+        if !path.is_file() {
+            return Ok(Netrc::default());
+        }
+        debug!("Reading {}", path.to_string_lossy());
+        let contents = fat_io_wrap_tokio(&path, read_to_string).await?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// The login for `host`, falling back to the `default` entry. The first matching machine wins.
+    pub fn lookup(&self, host: &str) -> Option<&NetrcLogin> {
+        self.machines
+            .iter()
+            .find(|(machine, _)| machine.eq_ignore_ascii_case(host))
+            .map(|(_, login)| login)
+            .or(self.default.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_machines_default_and_skips_macros() {
+        let netrc = Netrc::parse(
+            "# ci credentials\n\
+             machine lfs.example.com login ci password \"p a\\\"ss\"\n\
+             macdef init\n\
+             machine evil.example.com login macro password body\n\
+             \n\
+             machine git.example.com\n\
+             \tlogin dev # trailing comment\n\
+             \taccount ignored\n\
+             \tpassword secret\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(
+            netrc.lookup("LFS.example.com"),
+            Some(&NetrcLogin {
+                login: Some("ci".to_owned()),
+                password: Some("p a\"ss".to_owned()),
+            })
+        );
+        assert_eq!(
+            netrc.lookup("git.example.com"),
+            Some(&NetrcLogin {
+                login: Some("dev".to_owned()),
+                password: Some("secret".to_owned()),
+            })
+        );
+        assert_eq!(
+            netrc.lookup("evil.example.com"),
+            Some(&NetrcLogin {
+                login: Some("anonymous".to_owned()),
+                password: Some("guest".to_owned()),
+            })
+        );
+        assert_eq!(Netrc::parse("machine a login b").lookup("c"), None);
+    }
+}
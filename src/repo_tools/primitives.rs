@@ -1,12 +1,14 @@
 use crate::prelude::*;
+use crate::repo_tools::endpoint::LfsEndpoint;
 use futures_util::stream::StreamExt;
 use http::StatusCode;
-use reqwest::Client;
+use reqwest::{Client, Method};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::future::Future;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
@@ -15,7 +17,7 @@ use tempfile::NamedTempFile;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 use tokio::time::{sleep, timeout};
-use tracing::{debug, error, info};
+use tracing::{debug, error};
 use url::Url;
 use vg_errortools::{fat_io_wrap_tokio, FatIOError};
 
@@ -24,36 +26,6 @@ const VERSION_PREFIX: &str = "version";
 const OID_PREFIX: &str = "oid";
 const FILE_HEADER: &str = "version https://git-lfs.github.com/spec/v1";
 
-/// Finds the git repository root folder of the given file
-pub async fn get_repo_root<P: AsRef<Path>>(file_or_path: P) -> Result<PathBuf, LFSError> {
-    info!(
-        "Searching git repo root from path {}",
-        file_or_path.as_ref().to_string_lossy()
-    );
-    let repo_dir = fs::canonicalize(file_or_path.as_ref()).await.map_err(|e| {
-        LFSError::DirectoryTraversalError(format!(
-            "Problem getting the absolute path of {}: {}",
-            file_or_path.as_ref().to_string_lossy(),
-            e.to_string().as_str()
-        ))
-    })?;
-    let components: Vec<_> = repo_dir.components().collect();
-    for i in (0..components.len()).rev() {
-        let path = components
-            .iter()
-            .take(i)
-            .fold(PathBuf::new(), |a, b| a.join(b));
-        if path.join(".git").exists() {
-            return Ok(path);
-        }
-    }
-
-    Err(LFSError::DirectoryTraversalError(format!(
-        "Could not find .git in any parent path of the given path ({})",
-        file_or_path.as_ref().to_string_lossy()
-    )))
-}
-
 #[derive(PartialEq, Eq, Debug)]
 pub enum Hash {
     SHA256,
@@ -111,44 +83,118 @@ fn parse_lfs_string(input: &str) -> Result<MetaData, LFSError> {
     })
 }
 
-fn url_with_auth(url: &str, access_token: Option<&str>) -> Result<Url, LFSError> {
-    let mut url = Url::parse(url)?;
-    let username = if access_token.is_some() { "oauth2" } else { "" };
-    let result = url.set_username(username);
-    assert!(result.is_ok());
-    let result = url.set_password(access_token);
-    assert!(result.is_ok());
-    Ok(url)
+/// Redirects followed before giving up, same as reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str() == b.host_str()
+        && a.port_or_known_default() == b.port_or_known_default()
 }
 
-async fn handle_download(
-    meta_data: &MetaData,
-    repo_remote_url: &str,
-    access_token: Option<&str>,
-    randomizer_bytes: Option<usize>,
-    temp_dir: &Option<impl AsRef<Path>>,
-) -> Result<NamedTempFile, LFSError> {
+/// Sends a request and follows redirects itself, so credentials only reach the origin they are meant for:
+/// `credentials` and any `Authorization` header are dropped once a redirect leaves the origin of `url`
+async fn send_following_redirects(
+    client: &Client,
+    mut method: Method,
+    mut url: Url,
+    mut headers: http::HeaderMap,
+    mut credentials: http::HeaderMap,
+    mut body: Option<&serde_json::Value>,
+) -> Result<reqwest::Response, LFSError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = client
+            .request(method.clone(), url.clone())
+            .headers(credentials.clone())
+            .headers(headers.clone());
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+        let Some(location) = response
+            .headers()
+            .get(http::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+        else {
+            return Ok(response);
+        };
+        let next = url.join(location)?;
+        debug!("Following redirect to {next}");
+        if !same_origin(&url, &next) {
+            credentials.clear();
+            headers.remove(http::header::AUTHORIZATION);
+        }
+        if matches!(
+            response.status(),
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER
+        ) && method != Method::HEAD
+        {
+            method = Method::GET;
+            body = None;
+        }
+        url = next;
+    }
+    Err(LFSError::InvalidResponse(format!(
+        "More than {MAX_REDIRECTS} redirects"
+    )))
+}
+
+/// The answer to a batch request, with what is needed to download the announced objects
+pub struct Batch {
+    api_url: Url,
+    /// The credentials we sent, added to downloads from the LFS API origin
+    auth_headers: http::HeaderMap,
+    objects: Vec<Object>,
+}
+
+/// Requests download actions for all `objects` with a single batch API call, authenticated by `auth_headers`
+pub async fn request_batch(
+    objects: &[&MetaData],
+    lfs_endpoint: &LfsEndpoint,
+    client: &Client,
+    auth_headers: &HashMap<String, String>,
+) -> Result<Batch, LFSError> {
     const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
-    let client = Client::builder().build()?;
-    assert_eq!(meta_data.hash, Some(Hash::SHA256));
+    if objects
+        .iter()
+        .any(|meta_data| meta_data.hash != Some(Hash::SHA256))
+    {
+        return Err(LFSError::InvalidFormat(
+            "Only sha256 objects can be requested",
+        ));
+    }
     // we are implementing git-lfs batch API here: https://github.com/git-lfs/git-lfs/blob/main/docs/api/batch.md
-    let request = json!({
+    let mut request = json!({
         "operation": "download",
         "transfers": [ "basic" ],
-        "ref": {"name" : "refs/heads/main" },
-        "objects": vec!{Object::from_metadata(meta_data)},
+        "objects": objects.iter().map(|meta_data| Object::from_metadata(meta_data)).collect::<Vec<_>>(),
         "hash_algo": "sha256"
     });
+    if let Some(git_ref) = &lfs_endpoint.git_ref {
+        request["ref"] = json!({ "name": git_ref });
+    }
 
-    let request_url = repo_remote_url.to_owned() + "/info/lfs/objects/batch";
-    let request_url = url_with_auth(&request_url, access_token)?;
-    let response = client
-        .post(request_url.clone())
-        .header("Accept", MEDIA_TYPE)
-        .header("Content-Type", MEDIA_TYPE)
-        .json(&request)
-        .send()
-        .await?;
+    let api_url = Url::parse(&lfs_endpoint.url)?;
+    let request_url = Url::parse(&(lfs_endpoint.url.to_owned() + "/objects/batch"))?;
+    let auth_headers: http::HeaderMap = auth_headers.try_into()?;
+    let mut credentials: http::HeaderMap = (&lfs_endpoint.headers).try_into()?;
+    credentials.extend(auth_headers.clone());
+    let mut headers = http::HeaderMap::new();
+    headers.insert(http::header::ACCEPT, MEDIA_TYPE.try_into()?);
+    headers.insert(http::header::CONTENT_TYPE, MEDIA_TYPE.try_into()?);
+    debug!("Requesting {} objects", objects.len());
+    let response = send_following_redirects(
+        client,
+        Method::POST,
+        request_url,
+        headers,
+        credentials,
+        Some(&request),
+    )
+    .await?;
     if !response.status().is_success() {
         let status = response.status();
         error!(
@@ -163,108 +209,255 @@ async fn handle_download(
         };
     }
     let parsed_result = response.json::<ApiResult>().await?;
+    Ok(Batch {
+        api_url,
+        auth_headers,
+        objects: parsed_result.objects,
+    })
+}
+
+impl Batch {
+    /// Downloads `meta_data` following the action the server returned for it
+    pub async fn download(
+        &self,
+        meta_data: &MetaData,
+        client: &Client,
+        randomizer_bytes: Option<usize>,
+        temp_dir: &Option<impl AsRef<Path>>,
+    ) -> Result<NamedTempFile, LFSError> {
+        let object = self
+            .objects
+            .iter()
+            .find(|object| object.oid == meta_data.oid)
+            .ok_or(LFSError::RemoteFileNotFound(
+                "Object missing in the response of the LFS server",
+            ))?;
 
-    // download already, this could be moved out and made async
-    let object = parsed_result
-        .objects
-        .first()
-        .ok_or(LFSError::RemoteFileNotFound(
-            "Empty object list response from LFS server",
+        if let Some(error) = &object.error {
+            return Err(object_error(&object.oid, error.code, error.message.clone()));
+        }
+        let action = object.actions.as_ref().ok_or(LFSError::RemoteFileNotFound(
+            "No action received from LFS server",
         ))?;
 
-    let action = object.actions.as_ref().ok_or(LFSError::RemoteFileNotFound(
-        "No action received from LFS server",
-    ))?;
-
-    let url = url_with_auth(&action.download.href, access_token)?;
-    let headers: http::HeaderMap = (&action.download.header).try_into()?;
-    let download_request_builder = client.get(url).headers(headers);
-    let response = download_request_builder.send().await?;
-    let download_status = response.status();
-    if !download_status.is_success() {
-        let message = format!(
-            "Download failed: {} - body {}",
-            download_status,
-            response.text().await.unwrap_or_default()
-        );
-        return Err(LFSError::InvalidResponse(message));
+        // the server tells us everything the storage host needs, our own credentials are only added
+        // when it did not authenticate the action and the download stays on the LFS API origin
+        let url = Url::parse(&action.download.href)?;
+        let headers: http::HeaderMap = (&action.download.header).try_into()?;
+        let credentials = if object.authenticated != Some(true) && same_origin(&self.api_url, &url)
+        {
+            self.auth_headers.clone()
+        } else {
+            http::HeaderMap::new()
+        };
+        let response =
+            send_following_redirects(client, Method::GET, url, headers, credentials, None).await?;
+        let download_status = response.status();
+        if !download_status.is_success() {
+            let message = format!(
+                "Download failed: {} - body {}",
+                download_status,
+                response.text().await.unwrap_or_default()
+            );
+            return Err(LFSError::InvalidResponse(message));
+        }
+
+        // the length of an encoded body says nothing about the object, ObjectWriter checks it then
+        let identity = response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .is_none_or(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"identity"));
+        if let Some(length) = response.content_length().filter(|_| identity) {
+            if length != meta_data.size as u64 {
+                return Err(LFSError::SizeMismatch {
+                    expected: meta_data.size,
+                    actual: length as usize,
+                });
+            }
+        }
+
+        let mut writer = ObjectWriter::new(meta_data, randomizer_bytes, temp_dir).await?;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk_result) = stream.next().await {
+            writer.write(&chunk_result?)?;
+        }
+        writer.finish()
     }
+}
+
+async fn handle_download(
+    meta_data: &MetaData,
+    lfs_endpoint: &LfsEndpoint,
+    client: &Client,
+    auth_headers: &HashMap<String, String>,
+    randomizer_bytes: Option<usize>,
+    temp_dir: &Option<impl AsRef<Path>>,
+) -> Result<NamedTempFile, LFSError> {
+    request_batch(&[meta_data], lfs_endpoint, client, auth_headers)
+        .await?
+        .download(meta_data, client, randomizer_bytes, temp_dir)
+        .await
+}
 
-    debug!("creating temp file in current dir");
+/// Streams object data into a temp file next to the cache while hashing it,
+/// verifying it against the oid and size of the pointer
+pub struct ObjectWriter {
+    temp_file: NamedTempFile,
+    hasher: Sha256,
+    oid: String,
+    size: usize,
+    written: usize,
+}
 
-    const TEMP_SUFFIX: &str = ".lfstmp";
+impl ObjectWriter {
+    pub async fn new(
+        meta_data: &MetaData,
+        randomizer_bytes: Option<usize>,
+        temp_dir: &Option<impl AsRef<Path>>,
+    ) -> Result<Self, LFSError> {
+        debug!("creating temp file in current dir");
 
-    let temp_dir = if let Some(dir) = temp_dir {
-        dir.as_ref()
-    } else {
-        Path::new("./")
-    };
+        const TEMP_SUFFIX: &str = ".lfstmp";
 
-    let tmp_path = PathBuf::from(temp_dir).join(format!("{}{TEMP_SUFFIX}", &meta_data.oid));
-    if randomizer_bytes.is_none() && tmp_path.exists() {
-        debug!("temp file exists. Deleting");
-        fat_io_wrap_tokio(&tmp_path, fs::remove_file).await?;
+        let temp_dir = if let Some(dir) = temp_dir {
+            dir.as_ref()
+        } else {
+            Path::new("./")
+        };
+
+        let tmp_path = PathBuf::from(temp_dir).join(format!("{}{TEMP_SUFFIX}", &meta_data.oid));
+        if randomizer_bytes.is_none() && tmp_path.exists() {
+            debug!("temp file exists. Deleting");
+            fat_io_wrap_tokio(&tmp_path, fs::remove_file).await?;
+        }
+        let temp_file = tempfile::Builder::new()
+            .prefix(&meta_data.oid)
+            .suffix(TEMP_SUFFIX)
+            .rand_bytes(randomizer_bytes.unwrap_or_default())
+            .tempfile_in(temp_dir)
+            .map_err(|e| LFSError::TempFile(e.to_string()))?;
+
+        debug!("created tempfile: {:?}", &temp_file);
+        Ok(ObjectWriter {
+            temp_file,
+            hasher: Sha256::new(),
+            oid: meta_data.oid.clone(),
+            size: meta_data.size,
+            written: 0,
+        })
     }
-    let temp_file = tempfile::Builder::new()
-        .prefix(&meta_data.oid)
-        .suffix(TEMP_SUFFIX)
-        .rand_bytes(randomizer_bytes.unwrap_or_default())
-        .tempfile_in(temp_dir)
-        .map_err(|e| LFSError::TempFile(e.to_string()))?;
-
-    debug!("created tempfile: {:?}", &temp_file);
-
-    let mut hasher = Sha256::new();
-    let mut stream = response.bytes_stream();
-    while let Some(chunk_result) = stream.next().await {
-        let chunk = chunk_result?;
-        temp_file.as_file().write_all(&chunk).map_err(|e| {
+
+    /// Appends `chunk`, failing as soon as the data exceeds the size of the pointer
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), LFSError> {
+        self.written += chunk.len();
+        if self.written > self.size {
+            error!("Received more than the {} bytes of {}", self.size, self.oid);
+            return Err(LFSError::SizeMismatch {
+                expected: self.size,
+                actual: self.written,
+            });
+        }
+        self.temp_file.as_file().write_all(chunk).map_err(|e| {
             error!("Could not write tempfile");
             LFSError::FatFileIOError(FatIOError::from_std_io_err(
                 e,
-                temp_file.path().to_path_buf(),
+                self.temp_file.path().to_path_buf(),
             ))
         })?;
-        hasher.update(chunk);
+        self.hasher.update(chunk);
+        Ok(())
     }
-    temp_file.as_file().flush().map_err(|e| {
-        error!("Could not flush tempfile");
-        LFSError::FatFileIOError(FatIOError::from_std_io_err(
-            e,
-            temp_file.path().to_path_buf(),
-        ))
-    })?;
-
-    debug!("checking hash");
-
-    let result = hasher.finalize();
-    let hex_data = hex::decode(object.oid.as_bytes())?;
-    if result[..] == hex_data {
-        Ok(temp_file)
-    } else {
-        Err(LFSError::ChecksumMismatch)
+
+    /// Flushes the temp file and checks the data against the size and oid of the pointer
+    pub fn finish(self) -> Result<NamedTempFile, LFSError> {
+        if self.written != self.size {
+            return Err(LFSError::SizeMismatch {
+                expected: self.size,
+                actual: self.written,
+            });
+        }
+        let temp_file = self.temp_file;
+        temp_file.as_file().flush().map_err(|e| {
+            error!("Could not flush tempfile");
+            LFSError::FatFileIOError(FatIOError::from_std_io_err(
+                e,
+                temp_file.path().to_path_buf(),
+            ))
+        })?;
+
+        debug!("checking hash");
+
+        let result = self.hasher.finalize();
+        let hex_data = hex::decode(self.oid.as_bytes())?;
+        if result[..] == hex_data {
+            Ok(temp_file)
+        } else {
+            Err(LFSError::ChecksumMismatch)
+        }
     }
 }
 
 pub async fn download_file(
     meta_data: &MetaData,
-    repo_remote_url: &str,
-    access_token: Option<&str>,
-    max_retry: u32,
-    randomizer_bytes: Option<usize>,
-    connection_timeout: Option<u64>,
+    lfs_endpoint: &LfsEndpoint,
+    client: &Client,
+    auth_headers: &HashMap<String, String>,
+    options: &PullOptions,
     temp_dir: Option<impl AsRef<Path>>,
 ) -> Result<NamedTempFile, LFSError> {
+    retry_download(meta_data, options.max_retry, options.timeout, || {
+        handle_download(
+            meta_data,
+            lfs_endpoint,
+            client,
+            auth_headers,
+            options.randomizer_bytes,
+            &temp_dir,
+        )
+    })
+    .await
+}
+
+/// The error for the status `code` the LFS server returned for the object `oid`
+pub fn object_error(oid: &str, code: u16, message: String) -> LFSError {
+    let oid = oid.to_owned();
+    match code {
+        404 => LFSError::ObjectNotFound { oid, message },
+        410 => LFSError::ObjectRemoved { oid, message },
+        422 => LFSError::ObjectInvalid { oid, message },
+        507 => LFSError::StorageQuotaExceeded { oid, message },
+        code => LFSError::ObjectError { oid, code, message },
+    }
+}
+
+/// Whether `error` is a final answer of the LFS server for the object, which does not change when retrying.
+/// Other object errors, like 5xx codes, are retried as git-lfs does.
+pub fn is_object_error(error: &LFSError) -> bool {
+    matches!(
+        error,
+        LFSError::ObjectNotFound { .. }
+            | LFSError::ObjectRemoved { .. }
+            | LFSError::ObjectInvalid { .. }
+            | LFSError::StorageQuotaExceeded { .. }
+    )
+}
+
+/// Runs `download` up to `max_retry` times, each attempt limited by the effective timeout.
+/// `AccessDenied` and final errors the LFS server returned for the object are not retried.
+pub async fn retry_download<F, Fut>(
+    meta_data: &MetaData,
+    max_retry: u32,
+    connection_timeout: Option<u64>,
+    download: F,
+) -> Result<NamedTempFile, LFSError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<NamedTempFile, LFSError>>,
+{
     let effective_timeout = get_effective_timeout(connection_timeout, meta_data.size);
     for attempt in 1..=max_retry {
         debug!("Download attempt {attempt}");
-        let download = handle_download(
-            meta_data,
-            repo_remote_url,
-            access_token,
-            randomizer_bytes,
-            &temp_dir,
-        );
+        let download = download();
         let result = if let Some(seconds) = effective_timeout {
             timeout(Duration::from_secs(seconds), download).await
         } else {
@@ -277,7 +470,7 @@ pub async fn download_file(
                     return Ok(tempfile);
                 }
                 Err(e) => {
-                    if matches!(e, LFSError::AccessDenied) {
+                    if matches!(e, LFSError::AccessDenied) || is_object_error(&e) {
                         return Err(e);
                     }
                     error!("Download error: {e}");
@@ -296,7 +489,7 @@ pub async fn download_file(
 /// Some(0) => no timeout
 /// Some(x) => x seconds timeout
 /// None => automatic
-fn get_effective_timeout(timeout: Option<u64>, file_size_in_kb: usize) -> Option<u64> {
+pub fn get_effective_timeout(timeout: Option<u64>, file_size_in_kb: usize) -> Option<u64> {
     match timeout {
         Some(0) => {
             debug!("No timeout");
@@ -351,6 +544,16 @@ struct Object {
     size: usize,
     actions: Option<Action>,
     authenticated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ObjectStatus>,
+}
+
+/// The error the LFS server returns instead of actions for an object it cannot serve
+#[derive(Deserialize, Serialize, Debug)]
+struct ObjectStatus {
+    code: u16,
+    #[serde(default)]
+    message: String,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -361,6 +564,7 @@ struct Action {
 #[derive(Deserialize, Serialize, Debug)]
 struct Download {
     href: String,
+    #[serde(default)]
     header: HashMap<String, String>,
 }
 
@@ -371,14 +575,16 @@ impl Object {
             size: input.size,
             actions: None,
             authenticated: None,
+            error: None,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    const URL: &str = "https://dev.azure.com/buildvgmpsmi/buildvg/_git/git-lfs-test";
+    const URL: &str = "https://dev.azure.com/buildvgmpsmi/buildvg/_git/git-lfs-test.git/info/lfs";
     use super::*;
+    use crate::repo_tools::client::HttpClients;
     const LFS_TEST_DATA: &str = r#"version https://git-lfs.github.com/spec/v1
 oid sha256:0fae26606afd128d4d2f730462c8451b90931d25813e06e55239a2ca00e74c74
 size 226848"#;
@@ -394,12 +600,252 @@ size 226848"#;
         assert_eq!(parsed.hash, Some(Hash::SHA256));
     }
 
+    /// Serves a single request with `response` and returns the request head it received
+    async fn serve_once(
+        listener: tokio::net::TcpListener,
+        response: String,
+    ) -> tokio::task::JoinHandle<String> {
+        use tokio::io::AsyncWriteExt;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_lowercase()
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn credentials_are_dropped_on_cross_origin_redirects() {
+        let api = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let storage = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let storage_url = format!("http://{}/blob", storage.local_addr().unwrap());
+        let api_url = Url::parse(&format!("http://{}/object", api.local_addr().unwrap())).unwrap();
+        let api_request = serve_once(
+            api,
+            format!("HTTP/1.1 302 Found\r\nLocation: {storage_url}\r\nContent-Length: 0\r\n\r\n"),
+        )
+        .await;
+        let storage_request = serve_once(
+            storage,
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_owned(),
+        )
+        .await;
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-action", "kept".try_into().unwrap());
+        headers.insert(
+            http::header::AUTHORIZATION,
+            "RemoteAuth action".try_into().unwrap(),
+        );
+        let mut credentials = http::HeaderMap::new();
+        credentials.insert("private-token", "secret".try_into().unwrap());
+        let client = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response =
+            send_following_redirects(&client, Method::GET, api_url, headers, credentials, None)
+                .await
+                .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let api_request = api_request.await.unwrap();
+        assert!(api_request.contains("private-token: secret"));
+        assert!(api_request.contains("authorization: remoteauth action"));
+        let storage_request = storage_request.await.unwrap();
+        assert!(storage_request.starts_with("get /blob"));
+        assert!(storage_request.contains("x-action: kept"));
+        assert!(!storage_request.contains("private-token"));
+        assert!(!storage_request.contains("authorization"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn object_errors_of_the_batch_response() {
+        let api = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = LfsEndpoint {
+            url: format!("http://{}/lfs", api.local_addr().unwrap()),
+            ..Default::default()
+        };
+        let missing = MetaData {
+            version: "https://git-lfs.github.com/spec/v1".to_owned(),
+            oid: "a".repeat(64),
+            size: 1,
+            hash: Some(Hash::SHA256),
+        };
+        let over_quota = MetaData {
+            oid: "b".repeat(64),
+            ..parse_lfs_string(LFS_TEST_DATA).unwrap()
+        };
+        let body = json!({ "objects": [
+            { "oid": missing.oid, "size": 1, "error": { "code": 404, "message": "Object does not exist" } },
+            { "oid": over_quota.oid, "size": 226848, "error": { "code": 507, "message": "Quota exceeded" } },
+        ]})
+        .to_string();
+        let request = serve_once(
+            api,
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.git-lfs+json\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+        .await;
+        let client = Client::new();
+        let batch = request_batch(
+            &[&missing, &over_quota],
+            &endpoint,
+            &client,
+            &HashMap::new(),
+        )
+        .await
+        .expect("could not request batch");
+        assert!(!request.await.unwrap().contains("\"error\""));
+
+        let temp_dir: Option<&Path> = None;
+        match batch.download(&missing, &client, None, &temp_dir).await {
+            Err(LFSError::ObjectNotFound { oid, message }) => {
+                assert_eq!(oid, missing.oid);
+                assert_eq!(message, "Object does not exist");
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+        let result = batch.download(&over_quota, &client, None, &temp_dir).await;
+        assert!(matches!(
+            result,
+            Err(LFSError::StorageQuotaExceeded { oid, .. }) if oid == over_quota.oid
+        ));
+
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let result = retry_download(&missing, 3, Some(0), || async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(object_error(&missing.oid, 410, "Object removed".to_owned()))
+        })
+        .await;
+        assert!(matches!(result, Err(LFSError::ObjectRemoved { .. })));
+        assert_eq!(attempts.into_inner(), 1);
+
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let result = retry_download(&missing, 2, Some(0), || async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(object_error(&missing.oid, 503, "Try again".to_owned()))
+        })
+        .await;
+        assert!(matches!(result, Err(LFSError::ReachedMaxDownloadAttempt)));
+        assert_eq!(attempts.into_inner(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn downloads_are_verified_against_the_pointer() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let temp_dir = Some(dir.path());
+        let meta_data = MetaData {
+            version: "https://git-lfs.github.com/spec/v1".to_owned(),
+            oid: hex::encode(Sha256::digest(b"hello")),
+            size: 5,
+            hash: Some(Hash::SHA256),
+        };
+
+        let mut writer = ObjectWriter::new(&meta_data, None, &temp_dir)
+            .await
+            .unwrap();
+        writer.write(b"hel").unwrap();
+        writer.write(b"lo").unwrap();
+        let temp_file = writer.finish().expect("matching data was rejected");
+        assert_eq!(std::fs::read(temp_file.path()).unwrap(), b"hello");
+
+        let mut writer = ObjectWriter::new(&meta_data, None, &temp_dir)
+            .await
+            .unwrap();
+        writer.write(b"hello").unwrap();
+        assert!(matches!(
+            writer.write(b" world"),
+            Err(LFSError::SizeMismatch {
+                expected: 5,
+                actual: 11
+            })
+        ));
+        let mut writer = ObjectWriter::new(&meta_data, None, &temp_dir)
+            .await
+            .unwrap();
+        writer.write(b"hell").unwrap();
+        assert!(matches!(
+            writer.finish(),
+            Err(LFSError::SizeMismatch {
+                expected: 5,
+                actual: 4
+            })
+        ));
+        let mut writer = ObjectWriter::new(&meta_data, None, &temp_dir)
+            .await
+            .unwrap();
+        writer.write(b"world").unwrap();
+        assert!(matches!(writer.finish(), Err(LFSError::ChecksumMismatch)));
+
+        // the server echoes our oid, but announces a different object
+        let storage = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let href = format!("http://{}/blob", storage.local_addr().unwrap());
+        serve_once(
+            storage,
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world".to_owned(),
+        )
+        .await;
+        let batch = Batch {
+            api_url: Url::parse("http://127.0.0.1/lfs").unwrap(),
+            auth_headers: http::HeaderMap::new(),
+            objects: vec![Object {
+                oid: meta_data.oid.clone(),
+                size: meta_data.size,
+                actions: Some(Action {
+                    download: Download {
+                        href,
+                        header: HashMap::new(),
+                    },
+                }),
+                authenticated: None,
+                error: None,
+            }],
+        };
+        let result = batch
+            .download(&meta_data, &Client::new(), None, &temp_dir)
+            .await;
+        assert!(matches!(
+            result,
+            Err(LFSError::SizeMismatch {
+                expected: 5,
+                actual: 11
+            })
+        ));
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn try_pull_from_demo_repo() {
         let parsed = parse_lfs_string(LFS_TEST_DATA).expect("Could not parse demo-string!");
-        let temp_file = download_file(&parsed, URL, None, 3, None, Some(0), None::<&str>)
+        let endpoint = LfsEndpoint {
+            url: URL.to_owned(),
+            ..Default::default()
+        };
+        let options = PullOptions {
+            timeout: Some(0),
+            ..Default::default()
+        };
+        let client = HttpClients::new(&options.connection)
+            .get(&endpoint)
             .await
-            .expect("could not download file");
+            .expect("could not create client");
+        let temp_file = download_file(
+            &parsed,
+            &endpoint,
+            &client,
+            &HashMap::new(),
+            &options,
+            None::<&str>,
+        )
+        .await
+        .expect("could not download file");
         let temp_size = temp_file
             .as_file()
             .metadata()
@@ -0,0 +1,418 @@
+use crate::prelude::*;
+use crate::repo_tools::git_config::GitConfig;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::fs::read_to_string;
+use tracing::{debug, info};
+use vg_errortools::fat_io_wrap_tokio;
+
+/// The directories git uses for a checkout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repository {
+    /// Root of the working tree, `None` for bare repositories
+    pub work_tree: Option<PathBuf>,
+    /// The git dir of this checkout, e.g. `.git`, `.git/worktrees/<name>` or `.git/modules/<name>`
+    pub git_dir: PathBuf,
+    /// The directory holding config and LFS storage, shared between all worktrees
+    pub common_dir: PathBuf,
+}
+
+/// An initialised submodule listed in `.gitmodules`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submodule {
+    /// The name of the submodule in `.gitmodules`
+    pub name: String,
+    /// The checkout of the submodule with its own git dir
+    pub repository: Repository,
+}
+
+/// Reads a `gitdir: <path>` file, resolving the path relative to the file's directory
+async fn read_gitdir_file(git_file: &Path) -> Result<PathBuf, LFSError> {
+    let contents = fat_io_wrap_tokio(git_file, read_to_string).await?;
+    let git_dir = contents
+        .trim()
+        .strip_prefix("gitdir:")
+        .ok_or(LFSError::InvalidFormat(".git file contains no gitdir"))?
+        .trim();
+    Ok(git_file.parent().unwrap_or(Path::new("")).join(git_dir))
+}
+
+/// A directory is a git dir if it has the files git itself checks for
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+impl Repository {
+    /// Finds the repository containing `file_or_path`, following `.git` files of worktrees and
+    /// submodules and `commondir`. `GIT_DIR`, `GIT_WORK_TREE` and `GIT_COMMON_DIR` take precedence.
+    pub async fn discover(file_or_path: impl AsRef<Path>) -> Result<Self, LFSError> {
+        Self::discover_with_env(file_or_path.as_ref(), |name| std::env::var(name).ok()).await
+    }
+
+    async fn discover_with_env(
+        file_or_path: &Path,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, LFSError> {
+        info!(
+            "Searching git repo root from path {}",
+            file_or_path.to_string_lossy()
+        );
+        let (work_tree, git_dir) = if let Some(git_dir) = var("GIT_DIR") {
+            let work_tree = match var("GIT_WORK_TREE") {
+                Some(work_tree) => PathBuf::from(work_tree),
+                None => std::env::current_dir().map_err(|e| {
+                    LFSError::DirectoryTraversalError(format!(
+                        "Could not get current directory: {e}"
+                    ))
+                })?,
+            };
+            (Some(work_tree), PathBuf::from(git_dir))
+        } else {
+            let (work_tree, git_dir) = Self::find_git_dir(file_or_path).await?;
+            let work_tree = var("GIT_WORK_TREE").map(PathBuf::from).or(work_tree);
+            (work_tree, git_dir)
+        };
+
+        let common_dir = match var("GIT_COMMON_DIR") {
+            Some(common_dir) => PathBuf::from(common_dir),
+            None => {
+                let commondir_file = git_dir.join("commondir");
+                if commondir_file.is_file() {
+                    let common_dir = fat_io_wrap_tokio(&commondir_file, read_to_string).await?;
+                    git_dir.join(common_dir.trim())
+                } else {
+                    git_dir.clone()
+                }
+            }
+        };
+        let canonicalize = |path: PathBuf| std::fs::canonicalize(&path).unwrap_or(path);
+
+        let repository = Repository {
+            work_tree: work_tree.map(canonicalize),
+            git_dir: canonicalize(git_dir),
+            common_dir: canonicalize(common_dir),
+        };
+        debug!("Found repository {repository:?}");
+        Ok(repository)
+    }
+
+    /// Walks up from `file_or_path` to the first directory with a `.git` entry or which is a bare git dir
+    async fn find_git_dir(file_or_path: &Path) -> Result<(Option<PathBuf>, PathBuf), LFSError> {
+        let path = fs::canonicalize(file_or_path).await.map_err(|e| {
+            LFSError::DirectoryTraversalError(format!(
+                "Problem getting the absolute path of {}: {}",
+                file_or_path.to_string_lossy(),
+                e.to_string().as_str()
+            ))
+        })?;
+        let start = if path.is_dir() {
+            path.as_path()
+        } else {
+            path.parent().unwrap_or(&path)
+        };
+        for dir in start.ancestors() {
+            let dot_git = dir.join(".git");
+            if dot_git.is_dir() {
+                return Ok((Some(dir.to_path_buf()), dot_git));
+            }
+            if dot_git.is_file() {
+                return Ok((Some(dir.to_path_buf()), read_gitdir_file(&dot_git).await?));
+            }
+            if is_git_dir(dir) {
+                return Ok((None, dir.to_path_buf()));
+            }
+        }
+
+        Err(LFSError::DirectoryTraversalError(format!(
+            "Could not find .git in any parent path of the given path ({})",
+            file_or_path.to_string_lossy()
+        )))
+    }
+
+    /// Loads the git configuration of this repository
+    pub async fn config(&self) -> Result<GitConfig, LFSError> {
+        GitConfig::load(&self.common_dir, &self.git_dir).await
+    }
+
+    /// Lists the submodules from `.gitmodules` which are initialised, i.e. have a `.git` in their path
+    pub async fn submodules(&self) -> Result<Vec<Submodule>, LFSError> {
+        let Some(work_tree) = &self.work_tree else {
+            return Ok(Vec::new());
+        };
+        let gitmodules = work_tree.join(".gitmodules");
+        if !gitmodules.is_file() {
+            return Ok(Vec::new());
+        }
+        let config = GitConfig::from_file(gitmodules).await?;
+
+        let mut submodules = Vec::new();
+        for name in config.subsections("submodule") {
+            let Some(path) = config.get(&format!("submodule.{name}.path")) else {
+                debug!("Submodule '{name}' has no path");
+                continue;
+            };
+            let path = work_tree.join(path);
+            if !path.join(".git").exists() {
+                debug!("Submodule '{name}' is not initialised");
+                continue;
+            }
+            // GIT_DIR and friends describe the superproject only
+            let repository = Self::discover_with_env(&path, |_| None).await?;
+            submodules.push(Submodule {
+                name: name.to_owned(),
+                repository,
+            });
+        }
+        Ok(submodules)
+    }
+
+    /// Returns the branch checked out, `None` if `HEAD` is detached or unreadable
+    pub async fn head_branch(&self) -> Option<String> {
+        let head = self.read_head().await?;
+        head.strip_prefix("ref: refs/heads/").map(str::to_owned)
+    }
+
+    async fn read_head(&self) -> Option<String> {
+        let head = read_to_string(self.git_dir.join("HEAD"))
+            .await
+            .map_err(|e| debug!("Could not read HEAD: {e}"))
+            .ok()?;
+        Some(head.trim().to_owned())
+    }
+
+    /// Returns the local branches whose tip is `commit`, sorted by name
+    async fn branches_at(&self, commit: &str) -> Vec<String> {
+        let mut branches = Vec::new();
+        let packed_refs = read_to_string(self.common_dir.join("packed-refs"))
+            .await
+            .unwrap_or_default();
+        for line in packed_refs.lines() {
+            if let Some((sha, name)) = line.split_once(' ') {
+                if sha == commit {
+                    branches.extend(name.strip_prefix("refs/heads/").map(str::to_owned));
+                }
+            }
+        }
+        let heads_dir = self.common_dir.join("refs").join("heads");
+        let pattern = Path::new(&glob::Pattern::escape(&heads_dir.to_string_lossy()))
+            .join("**")
+            .join("*");
+        for path in glob::glob(&pattern.to_string_lossy())
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            if !path.is_file() {
+                continue;
+            }
+            let Ok(sha) = read_to_string(&path).await else {
+                continue;
+            };
+            let Ok(name) = path.strip_prefix(&heads_dir) else {
+                continue;
+            };
+            if sha.trim() == commit {
+                let name = name.to_string_lossy().replace('\\', "/");
+                if !branches.contains(&name) {
+                    branches.push(name);
+                }
+            }
+        }
+        branches.sort();
+        branches
+    }
+
+    /// Returns the remote ref the checkout corresponds to, sent to LFS servers to check branch permissions.
+    /// That is the upstream `branch.<name>.merge` of the checked out branch or `refs/heads/<name>` without one.
+    /// For a detached `HEAD` the branches pointing at the same commit are used, preferring those with an upstream.
+    pub async fn upstream_ref(&self, config: &GitConfig) -> Option<String> {
+        let head = self.read_head().await?;
+        let branches = match head.strip_prefix("ref: refs/heads/") {
+            Some(branch) => vec![branch.to_owned()],
+            None => self.branches_at(&head).await,
+        };
+        let upstream = branches
+            .iter()
+            .find_map(|branch| config.get(&format!("branch.{branch}.merge")));
+        match upstream {
+            Some(upstream) => Some(upstream.to_owned()),
+            None => branches
+                .first()
+                .map(|branch| format!("refs/heads/{branch}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_git_dir(git_dir: &Path) {
+        std::fs::create_dir_all(git_dir.join("objects")).unwrap();
+        std::fs::create_dir_all(git_dir.join("refs")).unwrap();
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn discovers_worktrees_submodules_and_bare_repos() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let main = root.join("main");
+        init_git_dir(&main.join(".git"));
+        std::fs::create_dir_all(main.join("sub").join("deep")).unwrap();
+
+        let repo = Repository::discover_with_env(&main.join("sub").join("deep"), |_| None)
+            .await
+            .unwrap();
+        assert_eq!(repo.work_tree.as_deref(), Some(main.as_path()));
+        assert_eq!(repo.git_dir, main.join(".git"));
+        assert_eq!(repo.common_dir, main.join(".git"));
+
+        // submodule: .git file pointing into the parent's modules folder
+        let module_dir = main.join(".git").join("modules").join("sub");
+        init_git_dir(&module_dir);
+        std::fs::write(
+            main.join("sub").join(".git"),
+            "gitdir: ../.git/modules/sub\n",
+        )
+        .unwrap();
+        let repo = Repository::discover_with_env(&main.join("sub").join("deep"), |_| None)
+            .await
+            .unwrap();
+        assert_eq!(repo.work_tree.as_deref(), Some(main.join("sub").as_path()));
+        assert_eq!(repo.git_dir, module_dir);
+        assert_eq!(repo.common_dir, module_dir);
+
+        // worktree: .git file pointing to .git/worktrees/<name> which has a commondir
+        let worktree_git_dir = main.join(".git").join("worktrees").join("feature");
+        std::fs::create_dir_all(&worktree_git_dir).unwrap();
+        std::fs::write(worktree_git_dir.join("commondir"), "../..\n").unwrap();
+        let worktree = root.join("feature");
+        std::fs::create_dir_all(&worktree).unwrap();
+        std::fs::write(
+            worktree.join(".git"),
+            format!("gitdir: {}\n", worktree_git_dir.to_string_lossy()),
+        )
+        .unwrap();
+        let repo = Repository::discover_with_env(&worktree, |_| None)
+            .await
+            .unwrap();
+        assert_eq!(repo.work_tree.as_deref(), Some(worktree.as_path()));
+        assert_eq!(repo.git_dir, worktree_git_dir);
+        assert_eq!(repo.common_dir, main.join(".git"));
+
+        // bare repository
+        let bare = root.join("bare.git");
+        init_git_dir(&bare);
+        let repo = Repository::discover_with_env(&bare, |_| None)
+            .await
+            .unwrap();
+        assert_eq!(repo.work_tree, None);
+        assert_eq!(repo.git_dir, bare);
+
+        // environment overrides
+        let env = |name: &str| match name {
+            "GIT_DIR" => Some(worktree_git_dir.to_string_lossy().to_string()),
+            "GIT_WORK_TREE" => Some(worktree.to_string_lossy().to_string()),
+            "GIT_COMMON_DIR" => Some(bare.to_string_lossy().to_string()),
+            _ => None,
+        };
+        let repo = Repository::discover_with_env(&root, env).await.unwrap();
+        assert_eq!(repo.work_tree.as_deref(), Some(worktree.as_path()));
+        assert_eq!(repo.git_dir, worktree_git_dir);
+        assert_eq!(repo.common_dir, bare);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn lists_initialised_submodules() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        init_git_dir(&root.join(".git"));
+        std::fs::write(
+            root.join(".gitmodules"),
+            "[submodule \"libs/core\"]\n\tpath = libs/core\n\turl = ../core.git\n\
+             [submodule \"docs\"]\n\tpath = docs\n\turl = ../docs.git\n",
+        )
+        .unwrap();
+        let module_dir = root.join(".git").join("modules").join("libs").join("core");
+        init_git_dir(&module_dir);
+        std::fs::create_dir_all(root.join("libs").join("core")).unwrap();
+        std::fs::write(
+            root.join("libs").join("core").join(".git"),
+            "gitdir: ../../.git/modules/libs/core\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+
+        let repo = Repository::discover_with_env(&root, |_| None)
+            .await
+            .unwrap();
+        let submodules = repo.submodules().await.unwrap();
+        assert_eq!(submodules.len(), 1);
+        assert_eq!(submodules[0].name, "libs/core");
+        assert_eq!(
+            submodules[0].repository.work_tree.as_deref(),
+            Some(root.join("libs").join("core").as_path())
+        );
+        assert_eq!(submodules[0].repository.common_dir, module_dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn upstream_ref_from_head_or_detached_commit() {
+        const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let git_dir = std::fs::canonicalize(dir.path()).unwrap().join(".git");
+        init_git_dir(&git_dir);
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+        let repo = Repository::discover_with_env(dir.path(), |_| None)
+            .await
+            .unwrap();
+
+        let config = GitConfig::default();
+        assert_eq!(
+            repo.upstream_ref(&config).await.as_deref(),
+            Some("refs/heads/feature/x")
+        );
+        let config =
+            GitConfig::parse("[branch \"feature/x\"]\n\tmerge = refs/heads/upstream\n").unwrap();
+        assert_eq!(
+            repo.upstream_ref(&config).await.as_deref(),
+            Some("refs/heads/upstream")
+        );
+
+        std::fs::write(git_dir.join("HEAD"), format!("{COMMIT}\n")).unwrap();
+        assert_eq!(repo.upstream_ref(&config).await, None);
+
+        std::fs::create_dir_all(git_dir.join("refs").join("heads").join("feature")).unwrap();
+        std::fs::write(
+            git_dir.join("refs").join("heads").join("feature").join("x"),
+            format!("{COMMIT}\n"),
+        )
+        .unwrap();
+        std::fs::write(
+            git_dir.join("packed-refs"),
+            format!("# pack-refs with: peeled\n{COMMIT} refs/heads/a-release\n"),
+        )
+        .unwrap();
+        assert_eq!(
+            repo.upstream_ref(&config).await.as_deref(),
+            Some("refs/heads/upstream")
+        );
+        assert_eq!(
+            repo.upstream_ref(&GitConfig::default()).await.as_deref(),
+            Some("refs/heads/a-release")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn find_current_repo_root_from_source_file() {
+        let current_file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(file!());
+        let repo = Repository::discover_with_env(&current_file_path, |_| None)
+            .await
+            .expect("Could not find repository of our own sources");
+        assert_eq!(
+            repo.work_tree,
+            Some(std::fs::canonicalize(env!("CARGO_MANIFEST_DIR")).unwrap())
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use crate::prelude::*;
+use crate::repo_tools::auth::AuthProvider;
+use crate::repo_tools::endpoint::RemoteUrl;
+use crate::repo_tools::git_config::GitConfig;
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+use tracing::debug;
+use url::Url;
+
+/// Responses are refreshed this long before they expire so they don't run out mid-request
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5);
+
+static AUTH_CACHE: LazyLock<Mutex<HashMap<String, CachedResponse>>> =
+    LazyLock::new(Default::default);
+
+struct CachedResponse {
+    response: SshAuthResponse,
+    expires: Option<SystemTime>,
+}
+
+/// The JSON answer of `git-lfs-authenticate`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SshAuthResponse {
+    /// The LFS API url to use instead of the one derived from the remote
+    pub href: Option<String>,
+    /// Headers to send with requests against `href`
+    #[serde(default)]
+    pub header: HashMap<String, String>,
+    /// Seconds until the header stops being valid
+    pub expires_in: Option<u64>,
+    /// RFC 3339 time at which the header stops being valid
+    pub expires_at: Option<String>,
+}
+
+impl SshAuthResponse {
+    /// When the header stops being valid, the earlier of `expires_in` and `expires_at`
+    fn expires(&self) -> Option<SystemTime> {
+        let expires_in = self
+            .expires_in
+            .map(|seconds| SystemTime::now() + Duration::from_secs(seconds));
+        let expires_at = self.expires_at.as_deref().and_then(parse_timestamp);
+        expires_in.into_iter().chain(expires_at).min()
+    }
+}
+
+/// Days between 1970-01-01 and the given date of the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parses RFC 3339 timestamps like `2016-07-29T16:45:38Z` or `2016-07-29T18:45:38.5+02:00`.
+/// Times before 1970, like the zero time some servers send, count as unset.
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let (clock, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(clock) => (clock, 0),
+        None => {
+            let (clock, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let seconds = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (
+                clock,
+                if offset.starts_with('-') {
+                    -seconds
+                } else {
+                    seconds
+                },
+            )
+        }
+    };
+    let mut clock = clock
+        .split('.')
+        .next()?
+        .splitn(3, ':')
+        .map(str::parse::<i64>);
+    let (hours, minutes, seconds) = (
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+    );
+    let timestamp =
+        days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds - offset;
+    Some(UNIX_EPOCH + Duration::from_secs(timestamp.try_into().ok()?))
+}
+
+/// The ssh program to run, resolved like git does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshCommand {
+    /// The program, or a shell command line if `use_shell` is set
+    pub program: String,
+    /// `GIT_SSH_COMMAND` and `core.sshCommand` may contain arguments and are run through a shell
+    pub use_shell: bool,
+}
+
+impl SshCommand {
+    /// `GIT_SSH_COMMAND`, then `core.sshCommand`, then `GIT_SSH`, then plain `ssh`
+    pub fn from_config(config: &GitConfig) -> Self {
+        Self::from_env(config, |name| std::env::var(name).ok())
+    }
+
+    fn from_env(config: &GitConfig, var: impl Fn(&str) -> Option<String>) -> Self {
+        let non_empty = |value: &String| !value.trim().is_empty();
+        if let Some(program) = var("GIT_SSH_COMMAND")
+            .filter(non_empty)
+            .or_else(|| config.get("core.sshcommand").map(str::to_owned))
+        {
+            SshCommand {
+                program,
+                use_shell: true,
+            }
+        } else {
+            SshCommand {
+                program: var("GIT_SSH")
+                    .filter(non_empty)
+                    .unwrap_or_else(|| "ssh".to_owned()),
+                use_shell: false,
+            }
+        }
+    }
+
+    pub fn command(&self, args: &[String]) -> Command {
+        if !self.use_shell {
+            let mut command = Command::new(&self.program);
+            command.args(args);
+            return command;
+        }
+        if cfg!(windows) {
+            let mut parts = self.program.split_whitespace();
+            let mut command = Command::new(parts.next().unwrap_or("ssh"));
+            command.args(parts).args(args);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("{} \"$@\"", self.program))
+                .arg(&self.program)
+                .args(args);
+            command
+        }
+    }
+}
+
+/// Arguments to run `program` for the repository of `remote` on the ssh server
+pub fn ssh_args(remote: &RemoteUrl, program: &str, operation: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(port) = remote.port {
+        args.push("-p".to_owned());
+        args.push(port.to_string());
+    }
+    args.push(match &remote.user {
+        Some(user) => format!("{user}@{}", remote.host),
+        None => remote.host.clone(),
+    });
+    args.push(format!(
+        "{program} {} {operation}",
+        remote.path.trim_start_matches('/')
+    ));
+    args
+}
+
+fn cache_key(ssh: &SshCommand, args: &[String]) -> String {
+    format!("{} {}", ssh.program, args.join(" "))
+}
+
+/// Runs `git-lfs-authenticate` on the ssh remote, reusing earlier answers until they expire
+pub async fn authenticate(
+    ssh: &SshCommand,
+    remote: &RemoteUrl,
+    operation: &str,
+) -> Result<SshAuthResponse, LFSError> {
+    let args = ssh_args(remote, "git-lfs-authenticate", operation);
+    let cache_key = cache_key(ssh, &args);
+    {
+        let cache = AUTH_CACHE.lock().expect("ssh auth cache poisoned");
+        if let Some(cached) = cache.get(&cache_key) {
+            if cached
+                .expires
+                .is_none_or(|expires| SystemTime::now() + EXPIRY_MARGIN < expires)
+            {
+                debug!("Using cached git-lfs-authenticate response");
+                return Ok(cached.response.clone());
+            }
+        }
+    }
+
+    debug!("Running {} {}", ssh.program, args.join(" "));
+    let output = ssh
+        .command(&args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| {
+            LFSError::SshAuthenticationFailed(format!("could not run {}: {e}", ssh.program))
+        })?;
+    if !output.status.success() {
+        return Err(LFSError::SshAuthenticationFailed(format!(
+            "{} exited with {}: {}",
+            ssh.program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let response: SshAuthResponse = serde_json::from_slice(&output.stdout).map_err(|e| {
+        LFSError::SshAuthenticationFailed(format!("invalid git-lfs-authenticate response: {e}"))
+    })?;
+
+    AUTH_CACHE.lock().expect("ssh auth cache poisoned").insert(
+        cache_key,
+        CachedResponse {
+            expires: response.expires(),
+            response: response.clone(),
+        },
+    );
+    Ok(response)
+}
+
+/// Drops the cached `git-lfs-authenticate` answer, so the next [`authenticate`] runs it again
+fn forget(ssh: &SshCommand, remote: &RemoteUrl, operation: &str) {
+    let args = ssh_args(remote, "git-lfs-authenticate", operation);
+    AUTH_CACHE
+        .lock()
+        .expect("ssh auth cache poisoned")
+        .remove(&cache_key(ssh, &args));
+}
+
+/// Authenticates with the headers from `git-lfs-authenticate`, which runs again
+/// once they expire or when the LFS server denies them
+pub struct SshAuth {
+    ssh: SshCommand,
+    remote: RemoteUrl,
+    /// Headers fetched again after a denial and not accepted yet, they are not refreshed when denied again
+    unproven: tokio::sync::Mutex<Option<HashMap<String, String>>>,
+}
+
+impl SshAuth {
+    const OPERATION: &'static str = "download";
+
+    pub fn new(ssh: SshCommand, remote: RemoteUrl) -> Self {
+        SshAuth {
+            ssh,
+            remote,
+            unproven: Default::default(),
+        }
+    }
+
+    async fn current(&self) -> Result<HashMap<String, String>, LFSError> {
+        Ok(authenticate(&self.ssh, &self.remote, Self::OPERATION)
+            .await?
+            .header)
+    }
+}
+
+impl AuthProvider for SshAuth {
+    fn headers<'a>(
+        &'a self,
+        _url: &'a Url,
+    ) -> BoxFuture<'a, Result<HashMap<String, String>, LFSError>> {
+        Box::pin(async {
+            // the lock is held while ssh runs, so concurrent requests wait for its answer
+            let _unproven = self.unproven.lock().await;
+            self.current().await
+        })
+    }
+
+    fn rejected<'a>(
+        &'a self,
+        _url: &'a Url,
+        headers: &'a HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<bool, LFSError>> {
+        Box::pin(async move {
+            let mut unproven = self.unproven.lock().await;
+            let current = self.current().await?;
+            if current != *headers {
+                // denied with headers that were replaced already, retry with the current ones
+                return Ok(true);
+            }
+            if unproven.as_ref() == Some(&current) {
+                return Ok(false);
+            }
+            debug!("git-lfs-authenticate headers were denied, running it again");
+            forget(&self.ssh, &self.remote, Self::OPERATION);
+            *unproven = Some(self.current().await?);
+            Ok(true)
+        })
+    }
+
+    fn accepted<'a>(
+        &'a self,
+        _url: &'a Url,
+        headers: &'a HashMap<String, String>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut unproven = self.unproven.lock().await;
+            if unproven.as_ref() == Some(headers) {
+                *unproven = None;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_command_precedence() {
+        let config = GitConfig::parse("[core]\n\tsshCommand = ssh -i key\n").unwrap();
+        let env = |name: &str| match name {
+            "GIT_SSH_COMMAND" => Some("ssh -o BatchMode=yes".to_owned()),
+            "GIT_SSH" => Some("plink".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            SshCommand::from_env(&config, env).program,
+            "ssh -o BatchMode=yes"
+        );
+        assert_eq!(
+            SshCommand::from_env(&config, |_| None).program,
+            "ssh -i key"
+        );
+        let ssh = SshCommand::from_env(&GitConfig::default(), |name| {
+            (name == "GIT_SSH").then(|| "plink".to_owned())
+        });
+        assert_eq!(
+            ssh,
+            SshCommand {
+                program: "plink".to_owned(),
+                use_shell: false
+            }
+        );
+    }
+
+    #[test]
+    fn ssh_arguments() {
+        let remote = RemoteUrl::parse("ssh://git@example.com:2222/group/repo.git").unwrap();
+        assert_eq!(
+            ssh_args(&remote, "git-lfs-authenticate", "download"),
+            vec![
+                "-p",
+                "2222",
+                "git@example.com",
+                "git-lfs-authenticate group/repo.git download"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn authenticate_with_fake_ssh() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let log = dir.path().join("calls.log");
+        let script = dir.path().join("fake-ssh");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\n\
+                 echo '{{\"href\": \"https://lfs.example.com/repo\", \
+                 \"header\": {{\"Authorization\": \"RemoteAuth abc\"}}, \"expires_in\": 3600}}'\n",
+                log.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let ssh = SshCommand {
+            program: script.to_string_lossy().to_string(),
+            use_shell: true,
+        };
+        let remote = RemoteUrl::parse("git@example.com:fake/repo.git").unwrap();
+        for _ in 0..2 {
+            let response = authenticate(&ssh, &remote, "download").await.unwrap();
+            assert_eq!(
+                response.href.as_deref(),
+                Some("https://lfs.example.com/repo")
+            );
+            assert_eq!(
+                response.header.get("Authorization").map(String::as_str),
+                Some("RemoteAuth abc")
+            );
+        }
+        let calls = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(
+            calls.lines().collect::<Vec<_>>(),
+            vec!["git@example.com git-lfs-authenticate fake/repo.git download"]
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let at = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(parse_timestamp("2016-07-29T16:45:38Z"), at(1469810738));
+        assert_eq!(
+            parse_timestamp("2016-07-29T18:45:38.25+02:00"),
+            at(1469810738)
+        );
+        assert_eq!(parse_timestamp("2016-07-29T14:45:38-02:00"), at(1469810738));
+        assert_eq!(parse_timestamp("0001-01-01T00:00:00Z"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    /// A fake ssh answering `RemoteAuth token-<n>` for its n-th call, with `expiry` in the JSON
+    #[cfg(unix)]
+    fn counting_ssh(dir: &std::path::Path, expiry: &str) -> SshCommand {
+        use std::os::unix::fs::PermissionsExt;
+
+        let count = dir.join("count");
+        let script = dir.join("fake-ssh");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nn=$(($(cat {count} 2>/dev/null || echo 0) + 1))\necho $n > {count}\n\
+                 echo \"{{\\\"header\\\": {{\\\"Authorization\\\": \\\"RemoteAuth token-$n\\\"}}, {expiry}}}\"\n",
+                count = count.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        SshCommand {
+            program: script.to_string_lossy().to_string(),
+            use_shell: true,
+        }
+    }
+
+    #[cfg(unix)]
+    fn token(headers: &HashMap<String, String>) -> &str {
+        headers.get("Authorization").map(String::as_str).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn ssh_auth_runs_again_after_expires_at() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let ssh = counting_ssh(
+            dir.path(),
+            "\\\"expires_in\\\": 3600, \\\"expires_at\\\": \\\"2000-01-01T00:00:00Z\\\"",
+        );
+        let auth = SshAuth::new(
+            ssh,
+            RemoteUrl::parse("git@example.com:expired/repo.git").unwrap(),
+        );
+        let url = Url::parse("https://lfs.example.com/repo").unwrap();
+        assert_eq!(
+            token(&auth.headers(&url).await.unwrap()),
+            "RemoteAuth token-1"
+        );
+        assert_eq!(
+            token(&auth.headers(&url).await.unwrap()),
+            "RemoteAuth token-2"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn ssh_auth_runs_again_when_denied() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let ssh = counting_ssh(dir.path(), "\\\"expires_in\\\": 3600");
+        let auth = SshAuth::new(
+            ssh,
+            RemoteUrl::parse("git@example.com:denied/repo.git").unwrap(),
+        );
+        let url = Url::parse("https://lfs.example.com/repo").unwrap();
+
+        let first = auth.headers(&url).await.unwrap();
+        assert_eq!(auth.headers(&url).await.unwrap(), first);
+        assert!(auth.rejected(&url, &first).await.unwrap());
+        let second = auth.headers(&url).await.unwrap();
+        assert_eq!(token(&second), "RemoteAuth token-2");
+        // a late denial of the replaced headers does not run it again
+        assert!(auth.rejected(&url, &first).await.unwrap());
+        assert_eq!(auth.headers(&url).await.unwrap(), second);
+        // the fresh headers are denied too, so the credentials are wrong
+        assert!(!auth.rejected(&url, &second).await.unwrap());
+        auth.accepted(&url, &second).await;
+        assert!(auth.rejected(&url, &second).await.unwrap());
+        assert_eq!(
+            token(&auth.headers(&url).await.unwrap()),
+            "RemoteAuth token-3"
+        );
+    }
+}
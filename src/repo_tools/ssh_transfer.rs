@@ -0,0 +1,659 @@
+use crate::prelude::*;
+use crate::repo_tools::endpoint::RemoteUrl;
+use crate::repo_tools::git_config::GitConfig;
+use crate::repo_tools::primitives::{object_error, retry_download, MetaData, ObjectWriter};
+use crate::repo_tools::ssh_auth::{ssh_args, SshCommand};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{LazyLock, Mutex};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, warn};
+
+// pkt-line framing as used by git and the git-lfs ssh protocol:
+// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md
+const MAX_PACKET_LENGTH: usize = 65520;
+const FLUSH_PACKET: &[u8] = b"0000";
+const DELIM_PACKET: &[u8] = b"0001";
+
+static SUPPORT_CACHE: LazyLock<Mutex<HashMap<String, bool>>> = LazyLock::new(Default::default);
+
+/// When to use `git-lfs-transfer` instead of `git-lfs-authenticate` and http, from `lfs.sshtransfer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshTransferMode {
+    /// Try `git-lfs-transfer` and fall back to http if the server doesn't support it
+    Negotiate,
+    /// Only use `git-lfs-transfer`
+    Always,
+    /// Never use `git-lfs-transfer`
+    Never,
+}
+
+impl SshTransferMode {
+    pub fn from_config(config: &GitConfig) -> Self {
+        match config.get("lfs.sshtransfer") {
+            Some("always") => SshTransferMode::Always,
+            Some("never") => SshTransferMode::Never,
+            Some("negotiate") | None => SshTransferMode::Negotiate,
+            Some(other) => {
+                warn!("Unknown lfs.sshtransfer value '{other}', using 'negotiate'");
+                SshTransferMode::Negotiate
+            }
+        }
+    }
+}
+
+fn protocol_error(message: impl Into<String>) -> LFSError {
+    LFSError::SshTransferError(message.into())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Packet {
+    Flush,
+    Delim,
+    Data(Vec<u8>),
+}
+
+fn packet_text(data: Vec<u8>) -> Result<String, LFSError> {
+    let mut text = String::from_utf8(data).map_err(|_| protocol_error("non utf-8 text packet"))?;
+    if text.ends_with('\n') {
+        text.pop();
+    }
+    Ok(text)
+}
+
+/// A `status <code>` answer with its arguments and message lines
+#[derive(Debug)]
+struct Status {
+    code: u16,
+    args: Vec<String>,
+    lines: Vec<String>,
+}
+
+impl Status {
+    fn ensure_ok(self, request: &str) -> Result<Self, LFSError> {
+        match self.code {
+            200 => Ok(self),
+            401 | 403 => Err(LFSError::AccessDenied),
+            code => Err(protocol_error(format!(
+                "{request} failed with status {code}: {}",
+                self.lines.join(" ")
+            ))),
+        }
+    }
+}
+
+struct PktLineConnection<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> PktLineConnection<R, W> {
+    async fn read_packet(&mut self) -> Result<Packet, LFSError> {
+        let mut length = [0u8; 4];
+        self.reader
+            .read_exact(&mut length)
+            .await
+            .map_err(|e| protocol_error(format!("could not read from ssh: {e}")))?;
+        let length = std::str::from_utf8(&length)
+            .ok()
+            .and_then(|length| usize::from_str_radix(length, 16).ok())
+            .ok_or_else(|| protocol_error("invalid pkt-line length"))?;
+        match length {
+            0 => Ok(Packet::Flush),
+            1 => Ok(Packet::Delim),
+            2 | 3 => Err(protocol_error("invalid pkt-line length")),
+            length if length > MAX_PACKET_LENGTH => Err(protocol_error("pkt-line too long")),
+            length => {
+                let mut data = vec![0; length - 4];
+                self.reader
+                    .read_exact(&mut data)
+                    .await
+                    .map_err(|e| protocol_error(format!("could not read from ssh: {e}")))?;
+                Ok(Packet::Data(data))
+            }
+        }
+    }
+
+    async fn write_raw(&mut self, data: &[u8]) -> Result<(), LFSError> {
+        self.writer
+            .write_all(data)
+            .await
+            .map_err(|e| protocol_error(format!("could not write to ssh: {e}")))
+    }
+
+    async fn write_text(&mut self, line: &str) -> Result<(), LFSError> {
+        let length = format!("{:04x}", line.len() + 5);
+        self.write_raw(length.as_bytes()).await?;
+        self.write_raw(line.as_bytes()).await?;
+        self.write_raw(b"\n").await
+    }
+
+    async fn finish_message(&mut self) -> Result<(), LFSError> {
+        self.write_raw(FLUSH_PACKET).await?;
+        self.writer
+            .flush()
+            .await
+            .map_err(|e| protocol_error(format!("could not write to ssh: {e}")))
+    }
+
+    /// Sends `command` followed by its arguments and a flush packet
+    async fn send_message(&mut self, command: &str, args: &[String]) -> Result<(), LFSError> {
+        self.write_text(command).await?;
+        for arg in args {
+            self.write_text(arg).await?;
+        }
+        self.finish_message().await
+    }
+
+    /// Like `send_message`, with a delimiter and data lines after the arguments
+    async fn send_message_with_lines(
+        &mut self,
+        command: &str,
+        args: &[String],
+        lines: &[String],
+    ) -> Result<(), LFSError> {
+        self.write_text(command).await?;
+        for arg in args {
+            self.write_text(arg).await?;
+        }
+        self.write_raw(DELIM_PACKET).await?;
+        for line in lines {
+            self.write_text(line).await?;
+        }
+        self.finish_message().await
+    }
+
+    /// Reads a status answer, a successful answer's data section is passed to `on_data`
+    async fn read_status_with_data(
+        &mut self,
+        mut on_data: impl FnMut(&[u8]) -> Result<(), LFSError>,
+    ) -> Result<Status, LFSError> {
+        let code = match self.read_packet().await? {
+            Packet::Data(data) => packet_text(data)?
+                .strip_prefix("status ")
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| protocol_error("expected status line"))?,
+            _ => return Err(protocol_error("expected status line")),
+        };
+
+        let mut status = Status {
+            code,
+            args: Vec::new(),
+            lines: Vec::new(),
+        };
+        loop {
+            match self.read_packet().await? {
+                Packet::Flush => return Ok(status),
+                Packet::Delim => break,
+                Packet::Data(data) => status.args.push(packet_text(data)?),
+            }
+        }
+        loop {
+            match self.read_packet().await? {
+                Packet::Flush => return Ok(status),
+                Packet::Delim => return Err(protocol_error("unexpected delimiter packet")),
+                Packet::Data(data) if code == 200 => on_data(&data)?,
+                Packet::Data(data) => status.lines.push(packet_text(data)?),
+            }
+        }
+    }
+
+    async fn read_status(&mut self) -> Result<Status, LFSError> {
+        let mut lines = Vec::new();
+        let mut status = self
+            .read_status_with_data(|data| {
+                lines.push(packet_text(data.to_vec())?);
+                Ok(())
+            })
+            .await?;
+        status.lines.append(&mut lines);
+        Ok(status)
+    }
+
+    async fn negotiate_version(&mut self) -> Result<(), LFSError> {
+        let mut capabilities = Vec::new();
+        loop {
+            match self.read_packet().await? {
+                Packet::Flush => break,
+                Packet::Delim => return Err(protocol_error("unexpected delimiter packet")),
+                Packet::Data(data) => capabilities.push(packet_text(data)?),
+            }
+        }
+        if !capabilities.iter().any(|c| c == "version=1") {
+            return Err(protocol_error("server does not support protocol version 1"));
+        }
+        self.send_message("version 1", &[]).await?;
+        self.read_status().await?.ensure_ok("version negotiation")?;
+        Ok(())
+    }
+
+    async fn quit(&mut self) -> Result<(), LFSError> {
+        self.send_message("quit", &[]).await?;
+        self.read_status().await?.ensure_ok("quit")?;
+        Ok(())
+    }
+
+    /// Requests the download actions for all `objects` in one message, returning the arguments for
+    /// `get-object` by oid. Objects the server has no download action for are left out.
+    async fn batch(
+        &mut self,
+        objects: &[&MetaData],
+        git_ref: Option<&str>,
+    ) -> Result<HashMap<String, Vec<String>>, LFSError> {
+        let mut args = vec!["hash-algo=sha256".to_owned()];
+        args.extend(git_ref.map(|git_ref| format!("refname={git_ref}")));
+        let lines: Vec<_> = objects
+            .iter()
+            .map(|meta_data| format!("{} {}", meta_data.oid, meta_data.size))
+            .collect();
+        self.send_message_with_lines("batch", &args, &lines).await?;
+        let status = self.read_status().await?.ensure_ok("batch")?;
+        Ok(status
+            .lines
+            .iter()
+            .filter_map(|line| {
+                let mut parts = line.split(' ');
+                let oid = parts.next()?;
+                (parts.nth(1)? == "download")
+                    .then(|| (oid.to_owned(), parts.map(str::to_owned).collect()))
+            })
+            .collect())
+    }
+
+    async fn get_object(
+        &mut self,
+        oid: &str,
+        args: &[String],
+        writer: &mut ObjectWriter,
+    ) -> Result<(), LFSError> {
+        self.send_message(&format!("get-object {oid}"), args)
+            .await?;
+        // the whole answer is read even if writing fails, so the connection can be used further
+        let mut write_result = Ok(());
+        let status = self
+            .read_status_with_data(|data| {
+                if write_result.is_ok() {
+                    write_result = writer.write(data);
+                }
+                Ok(())
+            })
+            .await?;
+        if !matches!(status.code, 200 | 401 | 403) {
+            return Err(object_error(oid, status.code, status.lines.join(" ")));
+        }
+        status.ensure_ok("get-object")?;
+        write_result
+    }
+}
+
+/// Downloads objects with the `git-lfs-transfer` protocol over an ssh connection
+#[derive(Debug, Clone)]
+pub struct SshTransfer {
+    command: SshCommand,
+    remote: RemoteUrl,
+    git_ref: Option<String>,
+}
+
+type SshConnection =
+    PktLineConnection<BufReader<tokio::process::ChildStdout>, tokio::process::ChildStdin>;
+
+impl SshTransfer {
+    pub fn new(command: SshCommand, remote: RemoteUrl, git_ref: Option<String>) -> Self {
+        SshTransfer {
+            command,
+            remote,
+            git_ref,
+        }
+    }
+
+    /// Starts `git-lfs-transfer` on the remote and negotiates the protocol version
+    pub async fn connect(&self) -> Result<SshSession, LFSError> {
+        let args = ssh_args(&self.remote, "git-lfs-transfer", "download");
+        debug!("Running {} {}", self.command.program, args.join(" "));
+        let mut child = self
+            .command
+            .command(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| protocol_error(format!("could not run {}: {e}", self.command.program)))?;
+        let (Some(stdout), Some(stdin)) = (child.stdout.take(), child.stdin.take()) else {
+            return Err(protocol_error("could not open ssh pipes"));
+        };
+        let mut connection = PktLineConnection {
+            reader: BufReader::new(stdout),
+            writer: stdin,
+        };
+        if let Err(e) = connection.negotiate_version().await {
+            drop(connection);
+            let _ = child.start_kill();
+            let _ = child.wait().await;
+            return Err(e);
+        }
+        Ok(SshSession {
+            child,
+            connection,
+            actions: HashMap::new(),
+        })
+    }
+
+    /// Checks once per remote whether the server speaks `git-lfs-transfer`
+    pub async fn is_supported(&self) -> bool {
+        let key = format!("{} {:?}", self.command.program, self.remote);
+        if let Some(supported) = SUPPORT_CACHE
+            .lock()
+            .expect("ssh transfer cache poisoned")
+            .get(&key)
+        {
+            return *supported;
+        }
+        let supported = match self.connect().await {
+            Ok(session) => session.close().await.is_ok(),
+            Err(e) => {
+                debug!("git-lfs-transfer not available: {e}");
+                false
+            }
+        };
+        SUPPORT_CACHE
+            .lock()
+            .expect("ssh transfer cache poisoned")
+            .insert(key, supported);
+        supported
+    }
+
+    /// Downloads `objects` over the connection in `session`, each into a temp file in its directory.
+    /// The objects are requested with a single `batch` message, a failed download is retried
+    /// up to `max_retry` times over a new connection.
+    pub async fn download_files(
+        &self,
+        session: &Mutex<Option<SshSession>>,
+        objects: &[(&MetaData, &Path)],
+        max_retry: u32,
+        randomizer_bytes: Option<usize>,
+        connection_timeout: Option<u64>,
+    ) -> Result<Vec<NamedTempFile>, LFSError> {
+        let mut temp_files = Vec::with_capacity(objects.len());
+        for (index, (meta_data, temp_dir)) in objects.iter().enumerate() {
+            let pending: Vec<_> = objects[index..]
+                .iter()
+                .map(|(meta_data, _)| *meta_data)
+                .collect();
+            let temp_file = retry_download(meta_data, max_retry, connection_timeout, || {
+                self.download_once(session, &pending, randomizer_bytes, temp_dir)
+            })
+            .await?;
+            temp_files.push(temp_file);
+        }
+        Ok(temp_files)
+    }
+
+    /// Downloads the first of the `pending` objects, batching all of them if it has no action yet.
+    /// The connection is put back into `session` unless it broke or the download was cancelled.
+    async fn download_once(
+        &self,
+        session: &Mutex<Option<SshSession>>,
+        pending: &[&MetaData],
+        randomizer_bytes: Option<usize>,
+        temp_dir: &Path,
+    ) -> Result<NamedTempFile, LFSError> {
+        let taken = session.lock().expect("ssh session poisoned").take();
+        let mut current = match taken {
+            Some(current) => current,
+            None => self.connect().await?,
+        };
+        let result = current
+            .download(pending, self.git_ref.as_deref(), randomizer_bytes, temp_dir)
+            .await;
+        if !matches!(result, Err(LFSError::SshTransferError(_))) {
+            *session.lock().expect("ssh session poisoned") = Some(current);
+        }
+        result
+    }
+}
+
+/// A running `git-lfs-transfer` connection, used for all objects of a pull
+pub struct SshSession {
+    child: tokio::process::Child,
+    connection: SshConnection,
+    /// The `get-object` arguments of the batched objects not downloaded yet, by oid
+    actions: HashMap<String, Vec<String>>,
+}
+
+impl SshSession {
+    async fn download(
+        &mut self,
+        pending: &[&MetaData],
+        git_ref: Option<&str>,
+        randomizer_bytes: Option<usize>,
+        temp_dir: &Path,
+    ) -> Result<NamedTempFile, LFSError> {
+        let meta_data = pending[0];
+        if !self.actions.contains_key(&meta_data.oid) {
+            let actions = self.connection.batch(pending, git_ref).await?;
+            self.actions.extend(actions);
+        }
+        let args = self
+            .actions
+            .remove(&meta_data.oid)
+            .ok_or(LFSError::RemoteFileNotFound(
+                "No action received from LFS server",
+            ))?;
+        let mut writer = ObjectWriter::new(meta_data, randomizer_bytes, &Some(temp_dir)).await?;
+        self.connection
+            .get_object(&meta_data.oid, &args, &mut writer)
+            .await?;
+        writer.finish()
+    }
+
+    /// Ends the session and waits for ssh to exit, it is killed if the server does not quit cleanly
+    pub async fn close(self) -> Result<(), LFSError> {
+        let SshSession {
+            mut child,
+            mut connection,
+            ..
+        } = self;
+        let result = connection.quit().await;
+        drop(connection);
+        if result.is_err() {
+            let _ = child.start_kill();
+        }
+        let _ = child.wait().await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo_tools::primitives::Hash;
+
+    // sha256 of "hello"
+    const OID: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    // sha256 of "world"
+    const OTHER_OID: &str = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+
+    fn text(line: &str) -> Vec<u8> {
+        format!("{:04x}{line}\n", line.len() + 5).into_bytes()
+    }
+
+    /// Canned answers for a session downloading `objects`, each with its content and `get-object` status
+    fn server_responses(objects: &[(&str, &str, &str)]) -> Vec<u8> {
+        let mut responses = Vec::new();
+        // capabilities and version negotiation
+        responses.extend(text("version=1"));
+        responses.extend(FLUSH_PACKET);
+        responses.extend(text("status 200"));
+        responses.extend(FLUSH_PACKET);
+        // batch
+        responses.extend(text("status 200"));
+        responses.extend(DELIM_PACKET);
+        for (index, (oid, content, _)) in objects.iter().enumerate() {
+            responses.extend(text(&format!(
+                "{oid} {} download id={index} token=secret",
+                content.len()
+            )));
+        }
+        responses.extend(FLUSH_PACKET);
+        for (_, content, object_status) in objects {
+            // get-object
+            responses.extend(text(&format!("status {object_status}")));
+            responses.extend(text(&format!("size={}", content.len())));
+            responses.extend(DELIM_PACKET);
+            if *object_status == "200" {
+                responses.extend(format!("{:04x}{content}", content.len() + 4).into_bytes());
+            } else {
+                responses.extend(text("object gone"));
+            }
+            responses.extend(FLUSH_PACKET);
+        }
+        // quit
+        responses.extend(text("status 200"));
+        responses.extend(FLUSH_PACKET);
+        responses
+    }
+
+    fn meta_data(oid: &str) -> MetaData {
+        MetaData {
+            version: "https://git-lfs.github.com/spec/v1".to_owned(),
+            oid: oid.to_owned(),
+            size: 5,
+            hash: Some(Hash::SHA256),
+        }
+    }
+
+    /// A stub "ssh" that plays back canned server answers and records what the client sent
+    fn stub_transfer(dir: &Path, responses: &[u8]) -> SshTransfer {
+        let responses_file = dir.join("responses");
+        std::fs::write(&responses_file, responses).unwrap();
+        let script = dir.join("stub.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "echo \"$@\" > {dir}/args\ncat {responses}\ncat > {dir}/requests\n",
+                dir = dir.to_string_lossy(),
+                responses = responses_file.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        SshTransfer::new(
+            SshCommand {
+                program: format!("sh {}", script.to_string_lossy()),
+                use_shell: true,
+            },
+            RemoteUrl::parse("git@example.com:group/repo.git").unwrap(),
+            Some("refs/heads/main".to_owned()),
+        )
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn download_from_stub_server() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let transfer = stub_transfer(
+            dir.path(),
+            &server_responses(&[(OID, "hello", "200"), (OTHER_OID, "world", "200")]),
+        );
+        let session = Mutex::new(None);
+        let (hello, world) = (meta_data(OID), meta_data(OTHER_OID));
+        let temp_files = transfer
+            .download_files(
+                &session,
+                &[(&hello, dir.path()), (&world, dir.path())],
+                1,
+                None,
+                None,
+            )
+            .await
+            .expect("could not download from stub server");
+        session
+            .into_inner()
+            .unwrap()
+            .unwrap()
+            .close()
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(temp_files[0].path()).unwrap(), b"hello");
+        assert_eq!(std::fs::read(temp_files[1].path()).unwrap(), b"world");
+
+        let args = std::fs::read_to_string(dir.path().join("args")).unwrap();
+        assert_eq!(
+            args.trim(),
+            "git@example.com git-lfs-transfer group/repo.git download"
+        );
+        let requests = std::fs::read(dir.path().join("requests")).unwrap();
+        let mut expected = Vec::new();
+        expected.extend(text("version 1"));
+        expected.extend(FLUSH_PACKET);
+        expected.extend(text("batch"));
+        expected.extend(text("hash-algo=sha256"));
+        expected.extend(text("refname=refs/heads/main"));
+        expected.extend(DELIM_PACKET);
+        expected.extend(text(&format!("{OID} 5")));
+        expected.extend(text(&format!("{OTHER_OID} 5")));
+        expected.extend(FLUSH_PACKET);
+        for (index, oid) in [OID, OTHER_OID].iter().enumerate() {
+            expected.extend(text(&format!("get-object {oid}")));
+            expected.extend(text(&format!("id={index}")));
+            expected.extend(text("token=secret"));
+            expected.extend(FLUSH_PACKET);
+        }
+        expected.extend(text("quit"));
+        expected.extend(FLUSH_PACKET);
+        assert_eq!(
+            String::from_utf8_lossy(&requests),
+            String::from_utf8_lossy(&expected)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn error_status_from_stub_server() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let transfer = stub_transfer(dir.path(), &server_responses(&[(OID, "hello", "410")]));
+        let session = Mutex::new(None);
+        let result = transfer
+            .download_files(&session, &[(&meta_data(OID), dir.path())], 3, None, None)
+            .await;
+        match result {
+            Err(LFSError::ObjectRemoved { oid, message }) => {
+                assert_eq!(oid, OID);
+                assert_eq!(message, "object gone");
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+        // the connection is still usable after an object error
+        session
+            .into_inner()
+            .unwrap()
+            .unwrap()
+            .close()
+            .await
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn negotiate_with_stub_server() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let mut responses = Vec::new();
+        responses.extend(text("version=1"));
+        responses.extend(FLUSH_PACKET);
+        responses.extend(text("status 200"));
+        responses.extend(FLUSH_PACKET);
+        responses.extend(text("status 200"));
+        responses.extend(FLUSH_PACKET);
+        let transfer = stub_transfer(dir.path(), &responses);
+        assert!(transfer.is_supported().await);
+
+        // ssh has exited, so everything it received is recorded already
+        let requests = std::fs::read(dir.path().join("requests")).unwrap();
+        let mut expected = text("version 1");
+        expected.extend(FLUSH_PACKET);
+        expected.extend(text("quit"));
+        expected.extend(FLUSH_PACKET);
+        assert_eq!(requests, expected);
+    }
+}
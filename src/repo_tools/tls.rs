@@ -0,0 +1,280 @@
+use crate::prelude::*;
+use crate::repo_tools::git_config::{parse_bool, GitConfig};
+use reqwest::{Certificate, ClientBuilder, Identity};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, warn};
+use url::Url;
+use vg_errortools::fat_io_wrap_tokio;
+
+/// TLS settings for the LFS server. Unset fields are taken from the `http.<url>.ssl*` git config.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsOptions {
+    /// PEM file with CA certificates to trust in addition to the system ones, like `http.sslCAInfo`
+    pub ca_info: Option<PathBuf>,
+    /// Directory of PEM files with CA certificates to trust, like `http.sslCAPath`
+    pub ca_path: Option<PathBuf>,
+    /// PEM client certificate for mutual TLS, like `http.sslCert`
+    pub cert: Option<PathBuf>,
+    /// PKCS#8 PEM key of the client certificate, like `http.sslKey`. Defaults to the certificate file.
+    pub key: Option<PathBuf>,
+    /// `Some(false)` accepts any server certificate, like `http.sslVerify = false`
+    pub verify: Option<bool>,
+}
+
+/// Expands a leading `~/` of a git config path to the home directory
+fn config_path(value: &str) -> PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    match (value.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(value),
+    }
+}
+
+async fn read(path: &Path) -> Result<Vec<u8>, LFSError> {
+    Ok(fat_io_wrap_tokio(path, fs::read).await?)
+}
+
+fn invalid(path: &Path, e: reqwest::Error) -> LFSError {
+    LFSError::InvalidTlsConfig(format!("{}: {e}", path.to_string_lossy()))
+}
+
+impl TlsOptions {
+    /// Fills the fields that are not set from the git config entries most specific for `url`
+    pub(crate) fn with_config(&self, config: &GitConfig, url: &Url) -> Self {
+        let path = |key| config.get_for_url("http", key, url).map(config_path);
+        TlsOptions {
+            ca_info: self.ca_info.clone().or_else(|| path("sslcainfo")),
+            ca_path: self.ca_path.clone().or_else(|| path("sslcapath")),
+            cert: self.cert.clone().or_else(|| path("sslcert")),
+            key: self.key.clone().or_else(|| path("sslkey")),
+            verify: self.verify.or_else(|| {
+                config
+                    .get_for_url("http", "sslverify", url)
+                    .and_then(parse_bool)
+            }),
+        }
+    }
+
+    /// Configures `builder` with the CA certificates, client identity and verification mode
+    pub(crate) async fn apply(
+        &self,
+        mut builder: ClientBuilder,
+    ) -> Result<ClientBuilder, LFSError> {
+        if let Some(ca_info) = &self.ca_info {
+            debug!(
+                "Trusting CA certificates from {}",
+                ca_info.to_string_lossy()
+            );
+            for certificate in Certificate::from_pem_bundle(&read(ca_info).await?)
+                .map_err(|e| invalid(ca_info, e))?
+            {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(ca_path) = &self.ca_path {
+            debug!("Trusting CA certificates in {}", ca_path.to_string_lossy());
+            let mut entries = fat_io_wrap_tokio(ca_path, fs::read_dir).await?;
+            while let Some(entry) = entries.next_entry().await.map_err(|e| {
+                LFSError::InvalidTlsConfig(format!("{}: {e}", ca_path.to_string_lossy()))
+            })? {
+                let path = entry.path();
+                if !path.is_file() {
+                    continue;
+                }
+                match Certificate::from_pem_bundle(&read(&path).await?) {
+                    Ok(certificates) => {
+                        for certificate in certificates {
+                            builder = builder.add_root_certificate(certificate);
+                        }
+                    }
+                    Err(e) => debug!("Skipping {}: {e}", path.to_string_lossy()),
+                }
+            }
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), key) => {
+                debug!("Using client certificate {}", cert.to_string_lossy());
+                let key_path = key.as_ref().unwrap_or(cert);
+                let identity = Identity::from_pkcs8_pem(&read(cert).await?, &read(key_path).await?)
+                    .map_err(|e| invalid(cert, e))?;
+                builder = builder.identity(identity);
+            }
+            (None, Some(key)) => warn!(
+                "Ignoring client key {} without a client certificate",
+                key.to_string_lossy()
+            ),
+            (None, None) => {}
+        }
+        if self.verify == Some(false) {
+            warn!("TLS certificate verification is disabled");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    pub(crate) fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    /// Serves `ok` over TLS with a certificate for 127.0.0.1 signed by the test CA
+    pub(crate) async fn serve_tls() -> Url {
+        let identity = tokio_native_tls::native_tls::Identity::from_pkcs8(
+            &std::fs::read(fixture("server.pem")).unwrap(),
+            &std::fs::read(fixture("server.key")).unwrap(),
+        )
+        .unwrap();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            tokio_native_tls::native_tls::TlsAcceptor::new(identity).unwrap(),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("https://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let read = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..read]);
+                    }
+                    let response =
+                        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+        url
+    }
+
+    async fn get(tls: &TlsOptions, url: &Url) -> Result<String, LFSError> {
+        let client = tls.apply(reqwest::Client::builder()).await?.build()?;
+        Ok(client.get(url.clone()).send().await?.text().await?)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn trusts_configured_ca_and_reads_git_config() {
+        let url = serve_tls().await;
+        assert!(get(&TlsOptions::default(), &url).await.is_err());
+
+        let config = GitConfig::parse(&format!(
+            "[http \"https://127.0.0.1:{}\"]\n\tsslCAInfo = {}\n\tsslCert = {}\n\tsslKey = {}\n",
+            url.port().unwrap(),
+            fixture("ca.pem").to_string_lossy(),
+            fixture("client.pem").to_string_lossy(),
+            fixture("client.key").to_string_lossy(),
+        ))
+        .unwrap();
+        let tls = TlsOptions::default().with_config(&config, &url);
+        assert_eq!(tls.ca_info, Some(fixture("ca.pem")));
+        assert_eq!(get(&tls, &url).await.unwrap(), "ok");
+
+        let ca_path = TlsOptions {
+            ca_path: Some(fixture("")),
+            ..Default::default()
+        };
+        assert_eq!(get(&ca_path, &url).await.unwrap(), "ok");
+
+        let no_verify = TlsOptions {
+            verify: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(get(&no_verify, &url).await.unwrap(), "ok");
+
+        // explicit options win over git config
+        let config =
+            GitConfig::parse("[http]\n\tsslVerify = false\n\tsslCAInfo = /missing.pem\n").unwrap();
+        let tls = TlsOptions {
+            verify: Some(true),
+            ..Default::default()
+        }
+        .with_config(&config, &url);
+        assert_eq!(tls.verify, Some(true));
+        assert!(matches!(
+            get(&tls, &url).await,
+            Err(LFSError::FatFileIOError(_))
+        ));
+
+        let broken_cert = TlsOptions {
+            cert: Some(fixture("ca.pem")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            get(&broken_cert, &url).await,
+            Err(LFSError::InvalidTlsConfig(_))
+        ));
+    }
+
+    /// Serves `ok` over TLS like [`serve_tls`], but only to clients with a certificate signed by the test CA
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn serve_mtls() -> Url {
+        use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+        use std::io::{Read, Write};
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_private_key_file(fixture("server.key"), SslFiletype::PEM)
+            .unwrap();
+        acceptor
+            .set_certificate_chain_file(fixture("server.pem"))
+            .unwrap();
+        acceptor.set_ca_file(fixture("ca.pem")).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("https://{}/", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = acceptor.accept(stream.unwrap()) else {
+                    continue;
+                };
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let response =
+                    "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+                stream.write_all(response.as_bytes()).unwrap();
+                let _ = stream.shutdown();
+            }
+        });
+        url
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn authenticates_with_client_certificate() {
+        let url = serve_mtls();
+        let without_identity = TlsOptions {
+            ca_info: Some(fixture("ca.pem")),
+            ..Default::default()
+        };
+        assert!(get(&without_identity, &url).await.is_err());
+
+        let config = GitConfig::parse(&format!(
+            "[http]\n\tsslCAInfo = {}\n\tsslCert = {}\n\tsslKey = {}\n",
+            fixture("ca.pem").to_string_lossy(),
+            fixture("client.pem").to_string_lossy(),
+            fixture("client.key").to_string_lossy(),
+        ))
+        .unwrap();
+        let tls = TlsOptions::default().with_config(&config, &url);
+        assert_eq!(get(&tls, &url).await.unwrap(), "ok");
+    }
+}
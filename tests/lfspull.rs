@@ -33,6 +33,15 @@ size 226848
 
 const TEST_LFS_FILE_NAME: &str = "beer_tornado.mp4";
 
+fn test_options() -> PullOptions {
+    PullOptions {
+        max_retry: 3,
+        randomizer_bytes: Some(5),
+        timeout: Some(0),
+        ..Default::default()
+    }
+}
+
 #[given(expr = "the test-repository is correctly setup")]
 fn setup_fake_git_repo(world: &mut LFSWorld) {
     let temp_folder = temp_dir().join(Uuid::new_v4().to_string());
@@ -55,7 +64,7 @@ async fn pull_file_step(world: &mut LFSWorld) {
         .clone()
         .join(TEST_LFS_FILE_NAME);
     world.pull_result = Some(
-        lfspull::pull_file(file_path, None, 3, Some(5), Some(0))
+        lfspull::pull_file(file_path, None, &test_options())
             .await
             .expect("Could not pull file"),
     );
@@ -64,8 +73,8 @@ async fn pull_file_step(world: &mut LFSWorld) {
 #[when(expr = "pulling the complete directory")]
 async fn pull_directory(world: &mut LFSWorld) {
     let fake_repo = world.current_fake_repo.as_ref().unwrap().to_string_lossy();
-    let pattern = format!("{}/**/*", fake_repo);
-    let recurse_pull = lfspull::glob_recurse_pull_directory(&pattern, None, 3, Some(5), Some(0))
+    let pattern = format!("{fake_repo}/**/*");
+    let recurse_pull = lfspull::glob_recurse_pull_directory(&pattern, None, &test_options())
         .await
         .expect("Could not pull directory")
         .into_iter()